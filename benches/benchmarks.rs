@@ -42,7 +42,9 @@ fn create_storage(c: &mut Criterion) {
 }
 
 fn create_storage_large(c: &mut Criterion) {
-    struct A([f64; 64]);
+    struct A {
+        _data: [f64; 64],
+    }
     c.bench_function("Create Storage Large", |b| {
         b.iter(|| {
             let _storage = Components::<A>::default();
@@ -116,15 +118,15 @@ fn iter_mut_op_partialfill(c: &mut Criterion) {
 
 fn join_bitset_speed(c: &mut Criterion) {
     c.bench_function("2 Join Speed", |b| {
-        struct A(f32);
-        struct B(f32);
+        struct A;
+        struct B;
         let mut entities = Entities::default();
         let mut storage = Components::<A>::default();
         let mut storage2 = Components::<B>::default();
         for _ in 0..10000 {
             let e = entities.create();
-            storage.insert(e, A(1.0));
-            storage2.insert(e, B(1.0));
+            storage.insert(e, A);
+            storage2.insert(e, B);
         }
         b.iter(|| {
             join!(&mut storage && &storage2).for_each(|_| {});
@@ -146,22 +148,22 @@ fn join_iter_speed(c: &mut Criterion) {
         }
         b.iter(|| {
             join!(&mut storage && &storage2)
-                .for_each(|(s, s2)| s.unwrap().0 += s2.unwrap().0);
+                .for_each(|(s, s2)| s.0 += s2.0);
         });
     });
 }
 
 fn join_immut_iter(c: &mut Criterion) {
     c.bench_function("2 Imut Iter Speed", |b| {
-        struct A(f32);
-        struct B(f32);
+        struct A;
+        struct B;
         let mut entities = Entities::default();
         let mut storage = Components::<A>::default();
         let mut storage2 = Components::<B>::default();
         for _ in 0..10000 {
             let e = entities.create();
-            storage.insert(e, A(1.0));
-            storage2.insert(e, B(1.0));
+            storage.insert(e, A);
+            storage2.insert(e, B);
         }
         b.iter(|| {
             let mut count = 0;
@@ -189,9 +191,40 @@ fn join_mut_partialfill(c: &mut Criterion) {
         }
         b.iter(|| {
             join!(&mut storage && &storage2)
-                .for_each(|(s, s2)| s.unwrap().0 += s2.unwrap().0);
+                .for_each(|(s, s2)| s.0 += s2.0);
+        });
+    });
+}
+
+fn join_planned_order(c: &mut Criterion) {
+    struct A;
+    struct B;
+    let mut entities = Entities::default();
+    let mut huge = Components::<A>::default();
+    let mut tiny = Components::<B>::default();
+    for i in 0..60000 {
+        let e = entities.create();
+        huge.insert(e, A);
+        if i % 6000 == 0 {
+            tiny.insert(e, B);
+        }
+    }
+    let mut group = c.benchmark_group("Join huge and tiny storages");
+    // What `join!` did before planning: start from the first storage.
+    group.bench_function("In written order", |b| {
+        b.iter(|| {
+            let bound = huge.bitset().high_water().max(tiny.bitset().high_water());
+            let mut bitset = huge.bitset().bounded(bound);
+            bitset.bit_and(tiny.bitset());
+            assert_eq!(bitset.bit_count(), 10);
+        });
+    });
+    group.bench_function("Planned", |b| {
+        b.iter(|| {
+            assert_eq!(join_count!(&huge && &tiny), 10);
         });
     });
+    group.finish();
 }
 
 criterion_group!(
@@ -209,5 +242,6 @@ criterion_group!(
     join_iter_speed,
     join_immut_iter,
     join_mut_partialfill,
+    join_planned_order,
 );
 criterion_main!(group);
@@ -23,10 +23,10 @@ fn main() {
     // We take a mutable reference to the A component and an immutable
     // reference to the B component.
     join!(&mut storage && &storage2)
-        .for_each(|(s, s2)| s.unwrap().0 += s2.unwrap().0);
+        .for_each(|(s, s2)| s.0 += s2.0);
 
     // Same thing, but we also get the entities id that align with the
     // matched components.
     join!(&entities && &mut storage && &storage2)
-        .for_each(|(_e, s, s2)| s.unwrap().0 += s2.unwrap().0);
+        .for_each(|(_e, s, s2)| s.0 += s2.0);
 }
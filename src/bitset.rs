@@ -1,3 +1,8 @@
+use crate::BitSet;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::rc::Rc;
+
 // 2^32 gives  4 billion concurrent entities for 512MB   of ram per component
 // 2^24 gives 16 million concurrent entities for 2MB     of ram per component
 // 2^20 gives  1 million concurrent entities for 128KB   of ram per component
@@ -13,7 +18,7 @@ const BITSET_EXP: u32 = 24;
 #[cfg(feature = "keysize32")]
 const BITSET_EXP: u32 = 32;
 pub(crate) const BITSET_SIZE: usize = 2usize.saturating_pow(BITSET_EXP);
-pub(crate) const BITSET_SLICE_COUNT: usize = BITSET_SIZE / (32 * 8 / 8);
+pub(crate) const BITSET_SLICE_COUNT: usize = BITSET_SIZE / (32 * 8);
 
 /// The type of bitsets used to track entities in component storages.
 /// Mostly used to create caches.
@@ -24,3 +29,524 @@ pub type BitSetVec = Vec<[u32; 8]>;
 pub fn create_bitset() -> BitSetVec {
     vec![[0u32; 8]; BITSET_SLICE_COUNT]
 }
+
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+/// Returns a number that was never returned before.
+pub(crate) fn next_version() -> u64 {
+    NEXT_VERSION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A bitset made of 256 bits blocks along with a summary layer, where bit `i`
+/// of the summary is set when block `i` has at least one bit set.
+///
+/// This allows iterating over the set bits while skipping empty blocks and
+/// empty words at once, which is much faster on sparse bitsets.
+/// The bitset also tracks a high-water mark: one past the highest index that
+/// was ever set. Operations never look past it.
+/// Indices past the end of the bitset are considered unset.
+///
+/// Each bitset gets a globally unique id when it is created or cloned, and
+/// counts its own modifications. Two bitsets with the same version, made of
+/// both, have the same content, which allows caching the result of
+/// operations on them.
+#[derive(Debug)]
+pub struct LayeredBitSet {
+    blocks: BitSetVec,
+    summary: Vec<u32>,
+    high: usize,
+    /// The amount of bits set, kept up to date by each operation.
+    len: usize,
+    id: u64,
+    modifications: u64,
+}
+
+impl Default for LayeredBitSet {
+    fn default() -> Self {
+        LayeredBitSet {
+            blocks: vec![],
+            summary: vec![],
+            high: 0,
+            len: 0,
+            id: next_version(),
+            modifications: 0,
+        }
+    }
+}
+
+impl Clone for LayeredBitSet {
+    fn clone(&self) -> Self {
+        // The clone is modified independently, so it needs its own id.
+        LayeredBitSet {
+            blocks: self.blocks.clone(),
+            summary: self.summary.clone(),
+            high: self.high,
+            len: self.len,
+            id: next_version(),
+            modifications: 0,
+        }
+    }
+}
+
+impl LayeredBitSet {
+    /// Creates an empty bitset big enough to contain the index of each entity.
+    pub fn new() -> Self {
+        LayeredBitSet {
+            blocks: create_bitset(),
+            summary: vec![0; BITSET_SLICE_COUNT.div_ceil(32)],
+            high: 0,
+            len: 0,
+            id: next_version(),
+            modifications: 0,
+        }
+    }
+    /// Creates a bitset from its blocks, computing the summary layer.
+    pub fn from_blocks(blocks: BitSetVec) -> Self {
+        let mut bitset = LayeredBitSet {
+            summary: vec![0; blocks.len().div_ceil(32)],
+            high: 0,
+            len: 0,
+            id: next_version(),
+            modifications: 0,
+            blocks,
+        };
+        for block in 0..bitset.blocks.len() {
+            bitset.update_summary(block);
+            bitset.len += bitset.block_count(block);
+            if let Some(word) = bitset.blocks[block].iter().rposition(|w| *w != 0) {
+                let bits = bitset.blocks[block][word];
+                bitset.high = block * 256 + word * 32 + 32 - bits.leading_zeros() as usize;
+            }
+        }
+        bitset
+    }
+    /// Returns the blocks of this bitset, without the summary layer.
+    pub fn blocks(&self) -> &BitSetVec {
+        &self.blocks
+    }
+    /// Returns the high-water mark of this bitset.
+    /// All the bits at or after this index are unset.
+    pub fn high_water(&self) -> usize {
+        self.high
+    }
+    /// Returns the version of this bitset, which changes each time it is
+    /// modified: the unique id of the bitset and its amount of modifications.
+    pub fn version(&self) -> (u64, u64) {
+        (self.id, self.modifications)
+    }
+    /// The amount of blocks up to the high-water mark.
+    fn high_blocks(&self) -> usize {
+        self.high.div_ceil(256)
+    }
+    /// Returns a copy of the bits of this bitset up to `bound`.
+    /// The high-water mark of the copy is `bound`, so `bit_not` flips all the
+    /// bits before it.
+    /// Much cheaper than `clone` when the high-water mark is low.
+    pub fn bounded(&self, bound: usize) -> LayeredBitSet {
+        let mut bitset = LayeredBitSet::default();
+        bitset.copy_bounded(self, bound);
+        bitset
+    }
+    /// Same as `bounded`, but reuses the memory of this bitset.
+    pub fn copy_bounded(&mut self, source: &LayeredBitSet, bound: usize) -> &mut Self {
+        let count = bound.div_ceil(256);
+        let copied = count.min(source.high_blocks());
+        self.blocks.clear();
+        self.blocks.extend_from_slice(&source.blocks[..copied]);
+        self.blocks.resize(count, [0; 8]);
+        self.summary.clear();
+        self.summary.resize(count.div_ceil(32), 0);
+        self.high = bound;
+        self.modifications += 1;
+        let words = copied.div_ceil(32);
+        self.summary[..words].copy_from_slice(&source.summary[..words]);
+        if !copied.is_multiple_of(32) {
+            self.summary[words - 1] &= !(!0u32 << (copied % 32));
+        }
+        self.clear_past_high();
+        self.len = if bound >= source.high {
+            source.len
+        } else {
+            self.non_empty_blocks().map(|block| self.block_count(block)).sum()
+        };
+        self
+    }
+    /// Resets the bits between the high-water mark and the end of its block.
+    fn clear_past_high(&mut self) {
+        if !self.high.is_multiple_of(256) {
+            let block = self.high / 256;
+            for word in 0..8 {
+                let start = block * 256 + word * 32;
+                if start >= self.high {
+                    self.blocks[block][word] = 0;
+                } else if self.high - start < 32 {
+                    self.blocks[block][word] &= !(!0u32 << (self.high - start));
+                }
+            }
+            self.update_summary(block);
+        }
+    }
+    /// Returns the amount of bits set in `block`.
+    fn block_count(&self, block: usize) -> usize {
+        self.blocks[block].iter().map(|w| w.count_ones() as usize).sum()
+    }
+    /// Recomputes the summary bit of `block`.
+    fn update_summary(&mut self, block: usize) {
+        if self.blocks[block] == [0u32; 8] {
+            self.summary[block / 32] &= !(1 << (block % 32));
+        } else {
+            self.summary[block / 32] |= 1 << (block % 32);
+        }
+    }
+    /// Returns true if the bit at index `bit` is set.
+    pub fn bit_test(&self, bit: usize) -> bool {
+        bit < self.high && self.blocks.bit_test(bit)
+    }
+    /// Sets the bit at index `bit`.
+    pub fn bit_set(&mut self, bit: usize) -> &mut Self {
+        let block = bit / 256;
+        if block >= self.blocks.len() {
+            self.blocks.resize(block + 1, [0; 8]);
+            self.summary.resize((block + 1).div_ceil(32), 0);
+        }
+        if !self.bit_test(bit) {
+            self.len += 1;
+        }
+        self.blocks.bit_set(bit);
+        self.summary[block / 32] |= 1 << (block % 32);
+        self.high = self.high.max(bit + 1);
+        self.modifications += 1;
+        self
+    }
+    /// Resets the bit at index `bit`.
+    pub fn bit_reset(&mut self, bit: usize) -> &mut Self {
+        if bit < self.high {
+            if self.blocks.bit_test(bit) {
+                self.len -= 1;
+            }
+            self.blocks.bit_reset(bit);
+            self.update_summary(bit / 256);
+            self.modifications += 1;
+        }
+        self
+    }
+    /// Keeps only the bits that are also set in `rhs`.
+    /// Only the blocks marked as non-empty in the summary are visited.
+    pub fn bit_and(&mut self, rhs: &LayeredBitSet) -> &mut Self {
+        self.modifications += 1;
+        let rhs_words = rhs.high_blocks().div_ceil(32);
+        for s in 0..self.high_blocks().div_ceil(32) {
+            let mut word = self.summary[s];
+            let rhs_word = if s < rhs_words { rhs.summary[s] } else { 0 };
+            while word != 0 {
+                let block = s * 32 + word.trailing_zeros() as usize;
+                word &= word - 1;
+                self.len -= self.block_count(block);
+                if rhs_word & (1 << (block % 32)) == 0 {
+                    self.blocks[block] = [0; 8];
+                } else {
+                    self.blocks[block..=block].bit_and(&rhs.blocks[block..=block]);
+                }
+                self.len += self.block_count(block);
+                self.update_summary(block);
+            }
+        }
+        self
+    }
+    /// Removes the bits that are set in `rhs`.
+    pub fn bit_andnot(&mut self, rhs: &LayeredBitSet) -> &mut Self {
+        self.modifications += 1;
+        for s in 0..self.high_blocks().min(rhs.high_blocks()).div_ceil(32) {
+            let mut word = self.summary[s] & rhs.summary[s];
+            while word != 0 {
+                let block = s * 32 + word.trailing_zeros() as usize;
+                word &= word - 1;
+                self.len -= self.block_count(block);
+                self.blocks[block..=block].bit_andnot(&rhs.blocks[block..=block]);
+                self.len += self.block_count(block);
+                self.update_summary(block);
+            }
+        }
+        self
+    }
+    /// Sets the bits that are set in `rhs`.
+    pub fn bit_or(&mut self, rhs: &LayeredBitSet) -> &mut Self {
+        self.modifications += 1;
+        let rhs_blocks = rhs.high_blocks();
+        if self.blocks.len() < rhs_blocks {
+            self.blocks.resize(rhs_blocks, [0; 8]);
+            self.summary.resize(rhs_blocks.div_ceil(32), 0);
+        }
+        for s in 0..rhs_blocks.div_ceil(32) {
+            let mut word = rhs.summary[s];
+            self.summary[s] |= word;
+            while word != 0 {
+                let block = s * 32 + word.trailing_zeros() as usize;
+                word &= word - 1;
+                self.len -= self.block_count(block);
+                self.blocks[block..=block].bit_or(&rhs.blocks[block..=block]);
+                self.len += self.block_count(block);
+            }
+        }
+        self.high = self.high.max(rhs.high);
+        self
+    }
+    /// Flips the bits that are set in `rhs`.
+    pub fn bit_xor(&mut self, rhs: &LayeredBitSet) -> &mut Self {
+        self.modifications += 1;
+        let rhs_blocks = rhs.high_blocks();
+        if self.blocks.len() < rhs_blocks {
+            self.blocks.resize(rhs_blocks, [0; 8]);
+            self.summary.resize(rhs_blocks.div_ceil(32), 0);
+        }
+        for s in 0..rhs_blocks.div_ceil(32) {
+            let mut word = rhs.summary[s];
+            while word != 0 {
+                let block = s * 32 + word.trailing_zeros() as usize;
+                word &= word - 1;
+                self.len -= self.block_count(block);
+                self.blocks[block..=block].bit_xor(&rhs.blocks[block..=block]);
+                self.len += self.block_count(block);
+                self.update_summary(block);
+            }
+        }
+        self.high = self.high.max(rhs.high);
+        self
+    }
+    /// Flips all the bits of this bitset up to its high-water mark.
+    /// Use `bounded` first to choose up to which index bits are flipped.
+    pub fn bit_not(&mut self) -> &mut Self {
+        self.modifications += 1;
+        // All the bits at or after the high-water mark are unset.
+        self.len = self.high - self.len;
+        let count = self.high_blocks();
+        self.blocks[..count].bit_not();
+        for block in 0..count {
+            self.update_summary(block);
+        }
+        self.clear_past_high();
+        self
+    }
+    /// Returns the amount of bits set.
+    /// The count is kept up to date by each operation, so this is free.
+    pub fn bit_count(&self) -> usize {
+        self.len
+    }
+    /// Returns true if no bit is set. Only looks at the summary layer.
+    pub fn is_empty(&self) -> bool {
+        self.summary[..self.high_blocks().div_ceil(32)].iter().all(|w| *w == 0)
+    }
+    /// Returns the index of the first set bit at or after `from`, if any.
+    pub fn next_set(&self, from: usize) -> Option<usize> {
+        let block = from / 256;
+        if block >= self.high_blocks() {
+            return None;
+        }
+        // Look in the rest of the starting block.
+        let mut word = (from % 256) / 32;
+        let mut bits = self.blocks[block][word] & (!0u32 << (from % 32));
+        loop {
+            if bits != 0 {
+                return Some(block * 256 + word * 32 + bits.trailing_zeros() as usize);
+            }
+            word += 1;
+            if word >= 8 {
+                break;
+            }
+            bits = self.blocks[block][word];
+        }
+        // Then use the summary to jump to the next non-empty block.
+        let next = block + 1;
+        let words = self.high_blocks().div_ceil(32);
+        let mut s = next / 32;
+        if s >= words {
+            return None;
+        }
+        let mut summary = self.summary[s] & (!0u32).checked_shl((next % 32) as u32).unwrap_or(0);
+        loop {
+            if summary != 0 {
+                let block = s * 32 + summary.trailing_zeros() as usize;
+                return self.blocks[block]
+                    .iter()
+                    .position(|w| *w != 0)
+                    .map(|word| block * 256 + word * 32 + self.blocks[block][word].trailing_zeros() as usize);
+            }
+            s += 1;
+            if s >= words {
+                return None;
+            }
+            summary = self.summary[s];
+        }
+    }
+    /// Returns the index of the last set bit before `to`, if any.
+    pub fn prev_set(&self, to: usize) -> Option<usize> {
+        let to = to.min(self.high);
+        if to == 0 {
+            return None;
+        }
+        let last = to - 1;
+        let block = last / 256;
+        // Look in the start of the ending block.
+        let mut word = (last % 256) / 32;
+        let mut bits = self.blocks[block][word] & (!0u32 >> (31 - last % 32));
+        loop {
+            if bits != 0 {
+                return Some(block * 256 + word * 32 + 31 - bits.leading_zeros() as usize);
+            }
+            if word == 0 {
+                break;
+            }
+            word -= 1;
+            bits = self.blocks[block][word];
+        }
+        // Then use the summary to jump to the previous non-empty block.
+        if block == 0 {
+            return None;
+        }
+        let prev = block - 1;
+        let mut s = prev / 32;
+        let mut summary = self.summary[s] & (!0u32 >> (31 - prev % 32));
+        loop {
+            if summary != 0 {
+                let block = s * 32 + 31 - summary.leading_zeros() as usize;
+                return self.blocks[block]
+                    .iter()
+                    .rposition(|w| *w != 0)
+                    .map(|word| block * 256 + word * 32 + 31 - self.blocks[block][word].leading_zeros() as usize);
+            }
+            if s == 0 {
+                return None;
+            }
+            s -= 1;
+            summary = self.summary[s];
+        }
+    }
+    /// Iterates over the indices of the non-empty blocks.
+    pub fn non_empty_blocks(&self) -> impl Iterator<Item = usize> + '_ {
+        self.summary[..self.high_blocks().div_ceil(32)]
+            .iter()
+            .enumerate()
+            .flat_map(|(s, word)| {
+                let mut word = *word;
+                std::iter::from_fn(move || {
+                    if word == 0 {
+                        None
+                    } else {
+                        let block = s * 32 + word.trailing_zeros() as usize;
+                        word &= word - 1;
+                        Some(block)
+                    }
+                })
+            })
+    }
+}
+
+/// Walks the set bits of a bitset from both ends, keeping count of the bits
+/// left in between. Shared by the storage iterators.
+#[derive(Debug)]
+pub(crate) struct BitSetCursor {
+    front: usize,
+    back: usize,
+    remaining: usize,
+    bitset: Rc<LayeredBitSet>,
+}
+
+impl BitSetCursor {
+    pub(crate) fn new(bitset: Rc<LayeredBitSet>) -> Self {
+        BitSetCursor {
+            front: 0,
+            back: bitset.high_water(),
+            remaining: bitset.bit_count(),
+            bitset,
+        }
+    }
+    /// Returns the amount of set bits not visited yet.
+    pub(crate) fn remaining(&self) -> usize {
+        self.remaining
+    }
+    pub(crate) fn next_front(&mut self) -> Option<usize> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let id = self.bitset.next_set(self.front)?;
+        self.front = id + 1;
+        self.remaining -= 1;
+        Some(id)
+    }
+    pub(crate) fn next_back(&mut self) -> Option<usize> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let id = self.bitset.prev_set(self.back)?;
+        self.back = id;
+        self.remaining -= 1;
+        Some(id)
+    }
+}
+
+impl PartialEq for LayeredBitSet {
+    fn eq(&self, other: &Self) -> bool {
+        let empty = [0u32; 8];
+        (0..self.high_blocks().max(other.high_blocks())).all(|b| {
+            self.blocks.get(b).unwrap_or(&empty) == other.blocks.get(b).unwrap_or(&empty)
+        })
+    }
+}
+
+impl Eq for LayeredBitSet {}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    #[test]
+    fn layered_skip_ahead() {
+        let mut bitset = LayeredBitSet::new();
+        for i in [3, 40, 255, 256, 9000, BITSET_SIZE - 1].iter() {
+            bitset.bit_set(*i);
+        }
+        let mut found = vec![];
+        let mut i = 0;
+        while let Some(next) = bitset.next_set(i) {
+            found.push(next);
+            i = next + 1;
+        }
+        assert_eq!(found, vec![3, 40, 255, 256, 9000, BITSET_SIZE - 1]);
+        assert_eq!(bitset.non_empty_blocks().collect::<Vec<_>>(), vec![0, 1, 35, (BITSET_SIZE - 1) / 256]);
+        let mut found = vec![];
+        let mut i = BITSET_SIZE;
+        while let Some(prev) = bitset.prev_set(i) {
+            found.push(prev);
+            i = prev;
+        }
+        assert_eq!(found, vec![BITSET_SIZE - 1, 9000, 256, 255, 40, 3]);
+
+        let mut other = LayeredBitSet::new();
+        other.bit_set(40).bit_set(9000).bit_set(9001);
+        let mut and = bitset.clone();
+        and.bit_and(&other);
+        assert_eq!(and.next_set(0), Some(40));
+        assert_eq!(and.next_set(41), Some(9000));
+        assert_eq!(and.next_set(9001), None);
+        assert_eq!(and, LayeredBitSet::from_blocks(and.blocks().clone()));
+        // Clones are versioned separately from the original.
+        let version = and.version();
+        let mut copy = and.clone();
+        assert_eq!(and.version(), version);
+        copy.bit_set(1);
+        assert_ne!(copy.version(), and.version());
+
+        bitset.bit_andnot(&other).bit_or(&other);
+        bitset.bit_reset(9001).bit_reset(9000);
+        assert_eq!(bitset.next_set(257), Some(BITSET_SIZE - 1));
+        assert_eq!(bitset.bit_count(), 5);
+
+        // The kept count matches the bits actually set.
+        let popcount = |b: &LayeredBitSet| b.blocks().iter().flatten().map(|w| w.count_ones() as usize).sum::<usize>();
+        let mut bounded = bitset.bounded(300);
+        assert_eq!(bounded.bit_count(), 4);
+        bounded.bit_not().bit_xor(&other).bit_set(299).bit_set(299);
+        assert_eq!(bounded.bit_count(), popcount(&bounded));
+        bounded.bit_or(&bitset).bit_andnot(&other);
+        assert_eq!(bounded.bit_count(), popcount(&bounded));
+    }
+}
@@ -0,0 +1,256 @@
+use crate::{Join, JoinMask, LayeredBitSet};
+
+use std::marker::PhantomData;
+
+/// A point in time of a `Components`, to compare its modifications against.
+///
+/// Each storage counts its own mutable accesses, so a `Tick` is only
+/// meaningful for the storage that returned it. `Tick::default()` is older
+/// than any modification.
+///
+/// Each system keeps its own `Tick`, takes the modifications that happened
+/// after it and then replaces it with `Components::tick`. Systems running at
+/// different times each see every modification exactly once.
+///
+/// ```rust,ignore
+/// let mut transforms = Components::<Transform>::default().with_change_tracking();
+/// let mut last_run = Tick::default();
+/// // Each frame:
+/// let changed = transforms.changed(last_run);
+/// (&mut transforms, &changed).join().for_each(|(t, ())| {});
+/// last_run = transforms.tick();
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Tick(pub(crate) u64);
+
+/// When the component at an index was last added, changed and removed.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct ComponentTicks {
+    pub(crate) added: u64,
+    pub(crate) changed: u64,
+    pub(crate) removed: u64,
+}
+
+/// The change tracking of a `Components`, allocated only when enabled.
+#[derive(Default)]
+pub(crate) struct ChangeTicks {
+    /// One per element of the storage.
+    pub(crate) entries: Vec<ComponentTicks>,
+    /// The last tick of any entry, for each block of 256 entries. Allows
+    /// skipping the blocks that were not modified when building filters.
+    pub(crate) blocks: Vec<u64>,
+}
+
+impl ChangeTicks {
+    /// Grows to cover `len` entries.
+    pub(crate) fn resize(&mut self, len: usize) {
+        self.entries.resize(len, ComponentTicks::default());
+        self.blocks.resize(len.div_ceil(256), 0);
+    }
+    /// Returns the ticks of entry `id`, marking its block as modified at
+    /// `tick`.
+    pub(crate) fn stamp(&mut self, id: usize, tick: u64) -> &mut ComponentTicks {
+        self.blocks[id / 256] = tick;
+        &mut self.entries[id]
+    }
+    /// Marks the entries set in `bitset` as changed at `tick`.
+    pub(crate) fn mark_all(&mut self, bitset: &LayeredBitSet, tick: u64) {
+        for block in bitset.non_empty_blocks() {
+            self.blocks[block] = tick;
+            for (word, bits) in bitset.blocks()[block].iter().enumerate() {
+                let mut bits = *bits;
+                while bits != 0 {
+                    let id = block * 256 + word * 32 + bits.trailing_zeros() as usize;
+                    bits &= bits - 1;
+                    self.entries[id].changed = tick;
+                }
+            }
+        }
+    }
+    /// Builds a bitset of the indices whose ticks match `filter`, given
+    /// whether the index has a component. Only the blocks modified after
+    /// `since` are visited.
+    pub(crate) fn filter(
+        &self,
+        since: Tick,
+        present: impl Fn(usize) -> bool,
+        filter: impl Fn(&ComponentTicks, bool) -> bool,
+    ) -> LayeredBitSet {
+        let mut blocks = vec![[0u32; 8]; self.blocks.len()];
+        for (block, _) in self.blocks.iter().enumerate().filter(|(_, tick)| **tick > since.0) {
+            for id in block * 256..((block + 1) * 256).min(self.entries.len()) {
+                if filter(&self.entries[id], present(id)) {
+                    blocks[block][(id % 256) / 32] |= 1 << (id % 32);
+                }
+            }
+        }
+        LayeredBitSet::from_blocks(blocks)
+    }
+    /// Returns a pointer marking entries as changed at `tick`, used by the
+    /// mutable iterators.
+    pub(crate) fn ptr(ticks: Option<&mut ChangeTicks>, tick: u64) -> TicksPtr {
+        match ticks {
+            Some(ticks) => TicksPtr {
+                entries: ticks.entries.as_mut_ptr(),
+                blocks: ticks.blocks.as_mut_ptr(),
+                tick,
+            },
+            None => TicksPtr {
+                entries: std::ptr::null_mut(),
+                blocks: std::ptr::null_mut(),
+                tick,
+            },
+        }
+    }
+}
+
+/// Marks the components handed out by a mutable iterator as changed.
+/// Does nothing when change tracking is disabled.
+#[derive(Clone, Copy)]
+pub(crate) struct TicksPtr {
+    entries: *mut ComponentTicks,
+    blocks: *mut u64,
+    tick: u64,
+}
+
+impl TicksPtr {
+    /// Marks the entry `id` as changed.
+    ///
+    /// # Safety
+    /// `id` must be in bounds of the storage, and no other thread may mark
+    /// an entry of the same block at the same time.
+    pub(crate) unsafe fn mark_changed(&self, id: usize) {
+        if !self.entries.is_null() {
+            (*self.entries.add(id)).changed = self.tick;
+            *self.blocks.add(id / 256) = self.tick;
+        }
+    }
+}
+
+macro_rules! tick_filter {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        pub struct $name<T> {
+            bits: LayeredBitSet,
+            _phantom: PhantomData<T>,
+        }
+
+        impl<T> $name<T> {
+            pub(crate) fn from_bitset(bits: LayeredBitSet) -> Self {
+                $name {
+                    bits,
+                    _phantom: PhantomData,
+                }
+            }
+            /// Returns the matching entity indices.
+            pub fn bitset(&self) -> &LayeredBitSet {
+                &self.bits
+            }
+            /// Returns the amount of matching entity indices.
+            pub fn count(&self) -> usize {
+                self.bits.bit_count()
+            }
+            /// Returns true if no entity index matches.
+            pub fn is_empty(&self) -> bool {
+                self.bits.is_empty()
+            }
+            /// Iterates over the indices of `bitset`, returning whether each
+            /// one matches. Used by `join!`.
+            pub fn iter_with_bitset<'a>(
+                &'a self,
+                bitset: impl Into<std::rc::Rc<LayeredBitSet>>,
+            ) -> impl Iterator<Item = Option<()>> + 'a {
+                let bitset = bitset.into();
+                let mut current = 0;
+                std::iter::from_fn(move || {
+                    let id = bitset.next_set(current)?;
+                    current = id + 1;
+                    Some(if self.bits.bit_test(id) { Some(()) } else { None })
+                })
+            }
+        }
+
+        impl<'a, T> Join for &'a $name<T> {
+            type Item = ();
+            type Values = &'a LayeredBitSet;
+            type Chunk = ();
+            fn high_water(&self) -> usize {
+                self.bits.high_water()
+            }
+            fn mask(&self, _bound: usize) -> JoinMask<'_> {
+                JoinMask::Borrowed(&self.bits)
+            }
+            fn open(self) -> Self::Values {
+                &self.bits
+            }
+            fn contains(values: &Self::Values, id: usize) -> bool {
+                values.bit_test(id)
+            }
+            unsafe fn fetch(_values: &mut Self::Values, _id: usize) -> Self::Item {}
+            unsafe fn fetch_chunk(_values: &mut Self::Values, _block: usize) -> Self::Chunk {}
+        }
+    };
+}
+
+tick_filter!(
+    /// The entities whose component of type `T` was inserted or mutably
+    /// accessed after a `Tick`. Created by `Components::changed`.
+    Changed
+);
+tick_filter!(
+    /// The entities whose component of type `T` was inserted after a `Tick`.
+    /// Created by `Components::added`.
+    Added
+);
+tick_filter!(
+    /// The entity indices whose component of type `T` was removed after a
+    /// `Tick`. Created by `Components::removed`.
+    Removed
+);
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    #[test]
+    fn per_system_ticks() {
+        struct A(u32);
+        let mut entities = Entities::default();
+        let mut storage = Components::<A>::default().with_change_tracking();
+        let e1 = entities.create();
+        let e2 = entities.create();
+        let e3 = entities.create();
+        let mut system1 = Tick::default();
+        let mut system2 = Tick::default();
+        storage.insert(e1, A(0));
+        storage.insert(e2, A(0));
+
+        assert_eq!(storage.added(system1).count(), 2);
+        let changed = storage.changed(system1);
+        assert_eq!(join!(&entities && &changed).count(), 2);
+        system1 = storage.tick();
+        assert!(storage.changed(system1).is_empty());
+
+        storage.get_mut(e1).unwrap().0 += 1;
+        storage.insert(e2, A(1));
+        storage.insert(e3, A(0));
+        let added = storage.added(system1);
+        assert_eq!(added.bitset().next_set(0), Some(e3.index() as usize));
+        let changed = storage.changed(system1);
+        assert_eq!((&storage, &changed).join().count(), 3);
+        system1 = storage.tick();
+
+        storage.remove(e3);
+        let removed = storage.removed(system1);
+        join!(&mut storage && !&removed).for_each(|a| a.0 += 1);
+        assert_eq!(storage.changed(system1).count(), 2);
+        assert_eq!(storage.removed(system1).count(), 1);
+        // The second system has not run yet and sees everything at once.
+        assert_eq!(storage.changed(system2).count(), 2);
+        assert_eq!(storage.removed(system2).count(), 1);
+        system2 = storage.tick();
+        assert!(storage.changed(system2).is_empty());
+
+        storage.insert(e3, A(0));
+        assert!(storage.removed(system1).is_empty());
+    }
+}
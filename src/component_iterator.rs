@@ -1,60 +1,145 @@
-use crate::{BitSetVec, BitSet};
+use crate::{BitSetCursor, TicksPtr};
+
+use std::iter::FusedIterator;
 // TODO try to reuse code between the two iterators
 
 /// Iterates over components using a provided bitset.
 /// Each time the bitset has a 1 in index i, the iterator will fetch data
 /// from the storage at index i and return it as an `Option`.
+///
+/// One item is returned per bit set, so the exact length is known upfront.
 pub struct ComponentIterator<'a, T> {
-    pub(crate) current_id: usize,
+    pub(crate) cursor: BitSetCursor,
     pub(crate) max_id: usize,
     pub(crate) storage: &'a Vec<Option<T>>,
-    pub(crate) bitset: std::rc::Rc<BitSetVec>,
+}
+
+impl<'a, T> ComponentIterator<'a, T> {
+    fn fetch(&self, id: usize) -> Option<&'a T> {
+        // The bitset can go past the end of the storage when joining with
+        // `||` or `!`.
+        if id < self.max_id {
+            self.storage[id].as_ref()
+        } else {
+            None
+        }
+    }
 }
 
 impl<'a, T> Iterator for ComponentIterator<'a, T> {
     type Item = Option<&'a T>;
     fn next(&mut self) -> Option<Self::Item> {
-        while !self.bitset.bit_test(self.current_id) && self.current_id <= self.max_id {
-            self.current_id += 1;
-        }
-        let ret = if self.current_id < self.max_id {
-            Some(self.storage[self.current_id].as_ref())
-        } else {
-            None
-        };
-        self.current_id += 1;
-        ret
+        let id = self.cursor.next_front()?;
+        Some(self.fetch(id))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.cursor.remaining(), Some(self.cursor.remaining()))
+    }
+}
+
+impl<'a, T> DoubleEndedIterator for ComponentIterator<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let id = self.cursor.next_back()?;
+        Some(self.fetch(id))
     }
 }
 
+impl<'a, T> ExactSizeIterator for ComponentIterator<'a, T> {}
+
+impl<'a, T> FusedIterator for ComponentIterator<'a, T> {}
+
 /// Iterates over components using a provided bitset.
 /// Each time the bitset has a 1 in index i, the iterator will fetch data
 /// from the storage at index i and return it as an `Option`.
+///
+/// The storage is accessed through a raw pointer taken once, so that the
+/// references already returned stay valid while iterating.
 pub struct ComponentIteratorMut<'a, T> {
-    pub(crate) current_id: usize,
+    pub(crate) cursor: BitSetCursor,
     pub(crate) max_id: usize,
-    pub(crate) storage: &'a mut Vec<Option<T>>,
-    pub(crate) bitset: std::rc::Rc<BitSetVec>,
+    pub(crate) storage: *mut Option<T>,
+    /// Marks the returned components as changed.
+    pub(crate) ticks: TicksPtr,
+    pub(crate) _phantom: std::marker::PhantomData<&'a mut [Option<T>]>,
+}
+
+impl<'a, T> ComponentIteratorMut<'a, T> {
+    fn fetch(&mut self, id: usize) -> Option<&'a mut T> {
+        if id < self.max_id {
+            // Unsafe: the index is in bounds and the cursor visits each index
+            // only once from either end, so the returned references never
+            // alias. Reborrowing the whole storage here instead would
+            // invalidate them.
+            let component = unsafe { (*self.storage.add(id)).as_mut() };
+            if component.is_some() {
+                unsafe { self.ticks.mark_changed(id) };
+            }
+            component
+        } else {
+            None
+        }
+    }
 }
 
 impl<'a, T> Iterator for ComponentIteratorMut<'a, T> {
     type Item = Option<&'a mut T>;
     fn next(&mut self) -> Option<Self::Item> {
-        while !self.bitset.bit_test(self.current_id) && self.current_id <= self.max_id {
-            self.current_id += 1;
+        let id = self.cursor.next_front()?;
+        Some(self.fetch(id))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.cursor.remaining(), Some(self.cursor.remaining()))
+    }
+}
+
+impl<'a, T> DoubleEndedIterator for ComponentIteratorMut<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let id = self.cursor.next_back()?;
+        Some(self.fetch(id))
+    }
+}
+
+impl<'a, T> ExactSizeIterator for ComponentIteratorMut<'a, T> {}
+
+impl<'a, T> FusedIterator for ComponentIteratorMut<'a, T> {}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    // These tests keep multiple mutable references alive at once, so they
+    // should also be ran with `cargo miri test`.
+    #[test]
+    fn iter_mut_disjoint_references() {
+        let mut entities = Entities::default();
+        let mut storage = Components::<u32>::default();
+        let mut other = Components::<u32>::default();
+        for i in 0..20 {
+            let e = entities.create();
+            if i % 3 != 0 {
+                storage.insert(e, i);
+            }
+            if i % 2 == 0 {
+                other.insert(e, i);
+            }
         }
-        let ret = if self.current_id < self.max_id {
-            // Unsafe: Used to tell the compiler that we won't mutably borrow the
-            // same element from storage twice.
-            let r = self.storage[self.current_id].as_mut().map(|e| unsafe {
-                let ptr: *mut T = e;
-                &mut *ptr
-            });
-            Some(r)
-        } else {
-            None
-        };
-        self.current_id += 1;
-        ret
+        let bitset = storage.bitset().clone();
+        let refs = storage.iter_mut_with_bitset(bitset).flatten().collect::<Vec<_>>();
+        assert_eq!(refs.len(), 13);
+        for r in refs {
+            *r += 100;
+        }
+        let refs = join!(&mut storage && &other).collect::<Vec<_>>();
+        for (a, b) in refs {
+            *a += *b;
+        }
+        let mut iter = storage.iter_mut_with_bitset(other.bitset().clone());
+        assert_eq!(iter.len(), 10);
+        let first = iter.next().unwrap();
+        let last = iter.next_back().unwrap();
+        assert_eq!(iter.len(), 8);
+        let middle = iter.rev().flatten().collect::<Vec<_>>();
+        assert_eq!((first, last, middle.len(), *middle[0]), (None, None, 6, 16 + 100 + 16));
+        let sum = join!(&storage).sum::<u32>();
+        assert_eq!(sum, (0..20).filter(|i| i % 3 != 0).map(|i| i + 100).sum::<u32>() + 2 + 4 + 8 + 10 + 14 + 16);
     }
 }
@@ -1,59 +1,131 @@
-use crate::{create_bitset, Entity, BitSetVec, BitSet, ComponentIterator, ComponentIteratorMut, BITSET_SIZE};
+use crate::{Added, BitSetCursor, ChangeTicks, Changed, ComponentTicks, Entities, Entity, LayeredBitSet, ComponentIterator, ComponentIteratorMut, Join, JoinMask, Removed, Tick, TicksPtr, WeakReferences, BITSET_SIZE};
 
 use std::collections::HashMap;
 use std::any::{TypeId, Any};
 use std::sync::Mutex;
 use atomic_refcell_try::AtomicRefMut;
 
+#[doc(hidden)]
+pub type CleanupFn = Box<dyn Fn(AtomicRefMut<dyn Any+'static>, &[Entity]) + Send + Sync>;
+
 lazy_static::lazy_static! {
     #[doc(hidden)]
-    pub static ref COMPONENT_REGISTRY: Mutex<HashMap<TypeId, Box<dyn Fn(AtomicRefMut<dyn Any+'static>, &[Entity]) + Send + Sync>>> = Mutex::new(HashMap::default());
+    pub static ref COMPONENT_REGISTRY: Mutex<HashMap<TypeId, CleanupFn>> = Mutex::new(HashMap::default());
 }
 
+/// Registers the code removing the components of killed entities from a
+/// storage of type `S` in the `COMPONENT_REGISTRY`.
+pub(crate) fn register_cleanup<S: 'static>(remove: fn(&mut S, Entity)) {
+    // Registers all the component downcasting and cleaning code in one globally accessible
+    // place. This seems to be the best way of doing it that doesn't involve
+    // heavily modifying how the `world_dispatcher` crate works.
+    COMPONENT_REGISTRY.lock().unwrap().insert(TypeId::of::<S>(), Box::new(move |any, entities| {
+        let mut me = AtomicRefMut::map(any, |j| j.downcast_mut::<S>().unwrap());
+        for e in entities {
+            remove(&mut me, *e);
+        }
+    }));
+}
+
+/// A callback ran by `Components` when a component is attached to or
+/// detached from an `Entity`.
+pub type ComponentHook<T> = Box<dyn FnMut(Entity, &mut T) + Send + Sync>;
+
 /// Holds components of a given type indexed by `Entity`.
 /// We do not check if the given entity is alive here, this should be done using
 /// `Entities`.
 pub struct Components<T> {
-    bitset: BitSetVec,
+    bitset: LayeredBitSet,
     components: Vec<Option<T>>,
+    /// Only allocated when change tracking is enabled.
+    ticks: Option<ChangeTicks>,
+    /// Incremented on each mutable access, to stamp the modified components.
+    change_tick: u64,
+    on_insert: Option<ComponentHook<T>>,
+    on_remove: Option<ComponentHook<T>>,
 }
 
 impl<T: 'static> Default for Components<T> {
     fn default() -> Self {
-        // Registers all the component downcasting and cleaning code in one globally accessible
-        // place. This seems to be the best way of doing it that doesn't involve
-        // heavily modifying how the `world_dispatcher` crate works.
-        COMPONENT_REGISTRY.lock().unwrap().insert(TypeId::of::<Self>(), Box::new(|any, entities| {
-            let mut me = AtomicRefMut::map(any, |j| j.downcast_mut::<Self>().unwrap());
-            for e in entities {
-                me.remove(*e);
-            }
-        }));
+        register_cleanup::<Self>(|me, e| {
+            me.remove(e);
+        });
         Self {
-            bitset: create_bitset(),
+            bitset: LayeredBitSet::new(),
             // Approximation of a good default.
             components: Vec::with_capacity(BITSET_SIZE >> 4),
+            ticks: None,
+            change_tick: 0,
+            on_insert: None,
+            on_remove: None,
         }
     }
 }
 
 impl<T> Components<T> {
+    /// Sets a callback that runs each time a component is inserted,
+    /// including when `insert` replaces an existing component.
+    pub fn with_on_insert(mut self, hook: impl FnMut(Entity, &mut T) + Send + Sync + 'static) -> Self {
+        self.on_insert = Some(Box::new(hook));
+        self
+    }
+    /// Sets a callback that runs each time a component is removed, either by
+    /// `remove`, by being replaced in `insert` or by the killed entities cleanup.
+    ///
+    /// The component is only borrowed, since `remove` and `insert` still give
+    /// it back to the caller.
+    pub fn with_on_remove(mut self, hook: impl FnMut(Entity, &mut T) + Send + Sync + 'static) -> Self {
+        self.on_remove = Some(Box::new(hook));
+        self
+    }
+    /// Enables change tracking, needed by `changed`, `added` and `removed`.
+    ///
+    /// Each mutable access then also records when the accessed components
+    /// were modified, which takes 24 bytes per entity index.
+    pub fn with_change_tracking(mut self) -> Self {
+        let mut ticks = ChangeTicks::default();
+        ticks.resize(self.components.len());
+        self.ticks = Some(ticks);
+        self
+    }
+    /// Returns the current change tick of this storage. Modifications made
+    /// after this call are newer than it.
+    pub fn tick(&self) -> Tick {
+        Tick(self.change_tick)
+    }
+    /// Starts a new mutable access, returning its tick.
+    fn next_tick(&mut self) -> u64 {
+        self.change_tick += 1;
+        self.change_tick
+    }
     /// Inserts a component for the given `Entity` index.
     /// Returns the previous component, if any.
     pub fn insert(&mut self, entity: Entity, component: T) -> Option<T> {
+        let idx = entity.index() as usize;
         let mut insertion = Some(component);
-        if self.bitset.bit_test(entity.index() as usize) {
-            std::mem::swap(
-                &mut insertion,
-                &mut self.components[entity.index() as usize],
-            );
-            insertion
+        let tick = self.next_tick();
+        let added = !self.bitset.bit_test(idx);
+        if !added {
+            std::mem::swap(&mut insertion, &mut self.components[idx]);
+            if let (Some(hook), Some(old)) = (self.on_remove.as_mut(), insertion.as_mut()) {
+                hook(entity, old);
+            }
         } else {
-            self.allocate_enough(entity.index() as usize);
-            self.bitset.bit_set(entity.index() as usize);
-            self.components[entity.index() as usize] = insertion;
-            None
+            self.allocate_enough(idx);
+            self.bitset.bit_set(idx);
+            self.components[idx] = insertion.take();
+        }
+        if let Some(ticks) = self.ticks.as_mut() {
+            let entry = ticks.stamp(idx, tick);
+            entry.changed = tick;
+            if added {
+                entry.added = tick;
+            }
+        }
+        if let (Some(hook), Some(new)) = (self.on_insert.as_mut(), self.components[idx].as_mut()) {
+            hook(entity, new);
         }
+        insertion
     }
     /// Ensures that we have the vec filled at least until the `until`
     /// variable. Usually, set this to `entity.index`.
@@ -63,6 +135,9 @@ impl<T> Components<T> {
             for _ in 0..qty {
                 self.components.push(None);
             }
+            if let Some(ticks) = self.ticks.as_mut() {
+                ticks.resize(self.components.len());
+            }
         }
     }
     /// Gets an immutable reference to the component of `Entity`.
@@ -74,8 +149,13 @@ impl<T> Components<T> {
         }
     }
     /// Gets a mutable reference to the component of `Entity`.
+    /// The component is marked as changed.
     pub fn get_mut(&mut self, entity: Entity) -> Option<&mut T> {
         if self.bitset.bit_test(entity.index() as usize) {
+            let tick = self.next_tick();
+            if let Some(ticks) = self.ticks.as_mut() {
+                ticks.stamp(entity.index() as usize, tick).changed = tick;
+            }
             self.components[entity.index() as usize].as_mut()
         } else {
             None
@@ -88,8 +168,15 @@ impl<T> Components<T> {
         let idx = entity.index() as usize;
         if self.bitset.bit_test(idx) {
             self.bitset.bit_reset(idx);
+            let tick = self.next_tick();
+            if let Some(ticks) = self.ticks.as_mut() {
+                ticks.stamp(idx, tick).removed = tick;
+            }
             let mut ret = None;
             std::mem::swap(&mut ret, &mut self.components[idx]);
+            if let (Some(hook), Some(old)) = (self.on_remove.as_mut(), ret.as_mut()) {
+                hook(entity, old);
+            }
             ret
         } else {
             None
@@ -97,37 +184,88 @@ impl<T> Components<T> {
     }
     /// Iterates immutably over all components of this type.
     /// Very fast but doesn't allow joining with other component types.
-    pub fn iter<'a>(&'a self) -> impl Iterator<Item = &'a T> {
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
         self.components.iter().flatten()
     }
     /// Iterates mutably over all components of this type.
     /// Very fast but doesn't allow joining with other component types.
-    pub fn iter_mut<'a>(&'a mut self) -> impl Iterator<Item = &'a mut T> {
+    /// The components are marked as changed.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.mark_all_changed();
         self.components.iter_mut().flatten()
     }
+    /// Marks all the components as changed, before handing them out.
+    fn mark_all_changed(&mut self) {
+        let tick = self.next_tick();
+        if let Some(ticks) = self.ticks.as_mut() {
+            ticks.mark_all(&self.bitset, tick);
+        }
+    }
+    /// Iterates immutably and in parallel over all components of this type.
+    #[cfg(feature = "rayon")]
+    pub fn par_iter(&self) -> impl rayon::iter::ParallelIterator<Item = &T>
+    where
+        T: Sync,
+    {
+        use rayon::prelude::*;
+        self.components.par_iter().filter_map(|c| c.as_ref())
+    }
+    /// Iterates mutably and in parallel over all components of this type.
+    /// The components are marked as changed.
+    #[cfg(feature = "rayon")]
+    pub fn par_iter_mut(&mut self) -> impl rayon::iter::ParallelIterator<Item = &mut T>
+    where
+        T: Send,
+    {
+        use rayon::prelude::*;
+        self.mark_all_changed();
+        self.components.par_iter_mut().filter_map(|c| c.as_mut())
+    }
+    /// Returns a view over the components that can be split between threads.
+    /// Used by `par_join!`.
+    #[cfg(feature = "rayon")]
+    pub fn par_view(&self) -> crate::ParComponents<'_, T> {
+        crate::ParComponents {
+            storage: &self.components,
+        }
+    }
+    /// Returns a mutable view over the components that can be split between
+    /// threads. Used by `par_join!`.
+    #[cfg(feature = "rayon")]
+    pub fn par_view_mut(&mut self) -> crate::ParComponentsMut<'_, T> {
+        let tick = self.next_tick();
+        crate::ParComponentsMut {
+            storage: self.components.as_mut_ptr(),
+            ticks: ChangeTicks::ptr(self.ticks.as_mut(), tick),
+            len: self.components.len(),
+            _phantom: std::marker::PhantomData,
+        }
+    }
     /// Iterates immutably over the components of this type where `bitset`
     /// indicates the indices of entities.
     /// Slower than `iter()` but allows joining between multiple component types.
-    pub fn iter_with_bitset<'a>(&'a self, bitset: std::rc::Rc<BitSetVec>) -> ComponentIterator<'a, T> {
+    pub fn iter_with_bitset<'a>(&'a self, bitset: impl Into<std::rc::Rc<LayeredBitSet>>) -> ComponentIterator<'a, T> {
         ComponentIterator {
-            current_id: 0,
+            cursor: BitSetCursor::new(bitset.into()),
             max_id: self.components.len(),
             storage: &self.components,
-            bitset,
         }
     }
     /// Iterates mutable over the components of this type where `bitset`
     /// indicates the indices of entities.
     /// Slower than `iter()` but allows joining between multiple component types.
+    /// The returned components are marked as changed.
     pub fn iter_mut_with_bitset<'a>(
         &'a mut self,
-        bitset: std::rc::Rc<BitSetVec>,
+        bitset: impl Into<std::rc::Rc<LayeredBitSet>>,
     ) -> ComponentIteratorMut<'a, T> {
+        let tick = self.next_tick();
         ComponentIteratorMut {
-            current_id: 0,
+            cursor: BitSetCursor::new(bitset.into()),
             max_id: self.components.len(),
-            storage: &mut self.components,
-            bitset,
+            storage: self.components.as_mut_ptr(),
+            ticks: ChangeTicks::ptr(self.ticks.as_mut(), tick),
+            _phantom: std::marker::PhantomData,
         }
     }
     /// Returns the bitset indicating which entity indices have a component
@@ -139,9 +277,133 @@ impl<T> Components<T> {
     /// And finally, you can use bitset1 in `iter_with_bitset` and `iter_mut_with_bitset`.
     /// This will iterate over the components of the entity only for entities that have both
     /// components.
-    pub fn bitset(&self) -> &BitSetVec {
+    pub fn bitset(&self) -> &LayeredBitSet {
         &self.bitset
     }
+    /// Returns the entity indices whose component was inserted or mutably
+    /// accessed after `since`.
+    ///
+    /// Panics if change tracking is not enabled.
+    pub fn changed(&self, since: Tick) -> Changed<T> {
+        Changed::from_bitset(self.ticks_bitset(since, |t, present| present && t.changed > since.0))
+    }
+    /// Returns the entity indices whose component was inserted after `since`,
+    /// without replacing an existing one.
+    ///
+    /// Panics if change tracking is not enabled.
+    pub fn added(&self, since: Tick) -> Added<T> {
+        Added::from_bitset(self.ticks_bitset(since, |t, present| present && t.added > since.0))
+    }
+    /// Returns the entity indices whose component was removed after `since`
+    /// and that did not get a new one.
+    /// The entities may since have been killed, so only indices are kept.
+    ///
+    /// Panics if change tracking is not enabled.
+    pub fn removed(&self, since: Tick) -> Removed<T> {
+        Removed::from_bitset(self.ticks_bitset(since, |t, present| !present && t.removed > since.0))
+    }
+    /// Builds a bitset of the indices whose ticks match `filter`.
+    fn ticks_bitset(&self, since: Tick, filter: impl Fn(&ComponentTicks, bool) -> bool) -> LayeredBitSet {
+        self.ticks
+            .as_ref()
+            .expect("Change tracking is not enabled on this storage.")
+            .filter(since, |id| self.bitset.bit_test(id), filter)
+    }
+}
+
+/// The values of a `&mut Components<T>` being joined over.
+#[doc(hidden)]
+pub struct ComponentsMutValues<'a, T> {
+    storage: *mut Option<T>,
+    ticks: TicksPtr,
+    len: usize,
+    _phantom: std::marker::PhantomData<&'a mut [Option<T>]>,
+}
+
+impl<T: WeakReferences> Components<T> {
+    /// Clears the references to dead entities held by the components.
+    /// Meant to be called after killing entities, so that later uses of the
+    /// references do not have to check them again.
+    /// Only the components that were modified are marked as changed.
+    pub fn purge_dead(&mut self, entities: &Entities) {
+        let tick = self.next_tick();
+        for (id, c) in self.components.iter_mut().enumerate() {
+            if let Some(c) = c.as_mut() {
+                if c.purge_dead(entities) {
+                    if let Some(ticks) = self.ticks.as_mut() {
+                        ticks.stamp(id, tick).changed = tick;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a, T> Join for &'a Components<T> {
+    type Item = &'a T;
+    type Values = &'a [Option<T>];
+    type Chunk = &'a [Option<T>];
+    fn high_water(&self) -> usize {
+        self.bitset.high_water()
+    }
+    fn mask(&self, _bound: usize) -> JoinMask<'_> {
+        JoinMask::Borrowed(&self.bitset)
+    }
+    fn open(self) -> Self::Values {
+        &self.components
+    }
+    fn contains(values: &Self::Values, id: usize) -> bool {
+        matches!(values.get(id), Some(Some(_)))
+    }
+    unsafe fn fetch(values: &mut Self::Values, id: usize) -> Self::Item {
+        values[id].as_ref().unwrap()
+    }
+    unsafe fn fetch_chunk(values: &mut Self::Values, block: usize) -> Self::Chunk {
+        let start = (block * 256).min(values.len());
+        let end = (start + 256).min(values.len());
+        &values[start..end]
+    }
+}
+
+impl<'a, T> Join for &'a mut Components<T> {
+    type Item = &'a mut T;
+    type Values = ComponentsMutValues<'a, T>;
+    type Chunk = &'a mut [Option<T>];
+    fn high_water(&self) -> usize {
+        self.bitset.high_water()
+    }
+    fn mask(&self, _bound: usize) -> JoinMask<'_> {
+        JoinMask::Borrowed(&self.bitset)
+    }
+    fn open(self) -> Self::Values {
+        let tick = self.next_tick();
+        ComponentsMutValues {
+            storage: self.components.as_mut_ptr(),
+            ticks: ChangeTicks::ptr(self.ticks.as_mut(), tick),
+            len: self.components.len(),
+            _phantom: std::marker::PhantomData,
+        }
+    }
+    fn contains(values: &Self::Values, id: usize) -> bool {
+        // Unsafe: the index is checked against the storage length.
+        id < values.len && unsafe { (*values.storage.add(id)).is_some() }
+    }
+    unsafe fn fetch(values: &mut Self::Values, id: usize) -> Self::Item {
+        assert!(id < values.len);
+        values.ticks.mark_changed(id);
+        (*values.storage.add(id)).as_mut().unwrap()
+    }
+    unsafe fn fetch_chunk(values: &mut Self::Values, block: usize) -> Self::Chunk {
+        let start = (block * 256).min(values.len);
+        let end = (start + 256).min(values.len);
+        // Any component of the chunk can be modified.
+        for id in start..end {
+            if (*values.storage.add(id)).is_some() {
+                values.ticks.mark_changed(id);
+            }
+        }
+        std::slice::from_raw_parts_mut(values.storage.add(start), end - start)
+    }
 }
 
 #[cfg(test)]
@@ -164,6 +426,49 @@ mod tests {
         assert!(storage.get(e1).is_none());
         assert_eq!(storage.iter().cloned().collect::<Vec<_>>(), vec![A])
     }
+
+    #[test]
+    fn insert_remove_hooks() {
+        use std::sync::{Arc, Mutex};
+        use atomic_refcell_try::AtomicRefCell;
+        use std::any::Any;
+
+        let log = Arc::new(Mutex::new(vec![]));
+        let (log1, log2) = (log.clone(), log.clone());
+
+        let mut entities = Entities::default();
+        let e1 = entities.create();
+        let e2 = entities.create();
+
+        let mut storage = Components::<u32>::default()
+            .with_on_insert(move |e, c| log1.lock().unwrap().push(("insert", e, *c)))
+            .with_on_remove(move |e, c| log2.lock().unwrap().push(("remove", e, *c)));
+        storage.insert(e1, 1);
+        assert_eq!(storage.insert(e1, 2), Some(1));
+        storage.insert(e2, 3);
+        assert_eq!(storage.remove(e1), Some(2));
+        assert_eq!(storage.remove(e1), None);
+
+        entities.kill(e2);
+        let cell: AtomicRefCell<Box<dyn Any>> = AtomicRefCell::new(Box::new(storage));
+        let any = atomic_refcell_try::AtomicRefMut::map(cell.borrow_mut(), |b| &mut **b);
+        COMPONENT_REGISTRY.lock().unwrap()[&std::any::TypeId::of::<Components<u32>>()](
+            any,
+            entities.killed(),
+        );
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                ("insert", e1, 1),
+                ("remove", e1, 1),
+                ("insert", e1, 2),
+                ("insert", e2, 3),
+                ("remove", e1, 2),
+                ("remove", e2, 3),
+            ]
+        );
+    }
 }
 
 
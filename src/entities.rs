@@ -1,12 +1,10 @@
-use crate::{
-    create_bitset, BitSet, BitSetVec, Entity, EntityIterator, BITSET_SIZE, BITSET_SLICE_COUNT,
-};
+use crate::{BitSet, BitSetCursor, Entity, EntityIterator, Join, JoinMask, LayeredBitSet, BITSET_SIZE, BITSET_SLICE_COUNT};
 
 /// Holds a list of alive entities.
 /// It also holds a list of entities that were recently killed, which allows
 /// to remove components of deleted entities at the end of a game frame.
 pub struct Entities {
-    alive: BitSetVec,
+    alive: LayeredBitSet,
     generation: Vec<u32>,
     killed: Vec<Entity>,
     next_id: usize,
@@ -18,7 +16,7 @@ pub struct Entities {
 impl Default for Entities {
     fn default() -> Self {
         Self {
-            alive: create_bitset(),
+            alive: LayeredBitSet::new(),
             generation: vec![0u32; BITSET_SIZE],
             killed: vec![],
             next_id: 0,
@@ -40,7 +38,7 @@ impl Entities {
         } else {
             let mut section = 0;
             // Find section where at least one bit isn't set
-            while self.alive[section].bit_all() {
+            while self.alive.blocks()[section].bit_all() {
                 section += 1;
                 if section >= BITSET_SLICE_COUNT {
                     panic!("Exceeded maximum amount of concurrent entities.");
@@ -65,6 +63,14 @@ impl Entities {
         self.alive.bit_test(entity.index() as usize)
             && self.generation[entity.index() as usize] == entity.generation()
     }
+    /// Returns the alive `Entity` at the given index, if any.
+    pub fn entity(&self, index: u32) -> Option<Entity> {
+        if self.alive.bit_test(index as usize) {
+            Some(Entity::new(index, self.generation[index as usize]))
+        } else {
+            None
+        }
+    }
     /// Kill an entity.
     pub fn kill(&mut self, entity: Entity) {
         if self.alive.bit_test(entity.index() as usize) {
@@ -85,21 +91,55 @@ impl Entities {
     /// Returns a bitset where each index where the bit is set to 1 indicates
     /// the index of an alive entity.
     /// Useful for joining over `Entity` and `Component<T>` at the same time.
-    pub fn bitset(&self) -> &BitSetVec {
+    pub fn bitset(&self) -> &LayeredBitSet {
         &self.alive
     }
+    /// Returns a view over the alive entities that can be split between
+    /// threads. Used by `par_join!`.
+    #[cfg(feature = "rayon")]
+    pub fn par_view(&self) -> crate::ParEntities<'_> {
+        crate::ParEntities {
+            alive: &self.alive,
+            generations: &self.generation,
+            next_id: self.next_id,
+        }
+    }
     /// Iterates over entities using the provided bitset.
-    pub fn iter_with_bitset<'a>(&'a self, bitset: std::rc::Rc<BitSetVec>) -> EntityIterator<'a> {
+    pub fn iter_with_bitset<'a>(&'a self, bitset: impl Into<std::rc::Rc<LayeredBitSet>>) -> EntityIterator<'a> {
         EntityIterator {
-            current_id: 0,
+            cursor: BitSetCursor::new(bitset.into()),
             next_id: self.next_id,
             entities: &self.alive,
             generations: &self.generation,
-            bitset,
         }
     }
 }
 
+impl<'a> Join for &'a Entities {
+    type Item = Entity;
+    type Values = &'a Entities;
+    /// The generations of the entities of the block.
+    type Chunk = &'a [u32];
+    fn high_water(&self) -> usize {
+        self.alive.high_water()
+    }
+    fn mask(&self, _bound: usize) -> JoinMask<'_> {
+        JoinMask::Borrowed(&self.alive)
+    }
+    fn open(self) -> Self::Values {
+        self
+    }
+    fn contains(values: &Self::Values, id: usize) -> bool {
+        id < values.next_id && values.alive.bit_test(id)
+    }
+    unsafe fn fetch(values: &mut Self::Values, id: usize) -> Self::Item {
+        Entity::new(id as u32, values.generation[id])
+    }
+    unsafe fn fetch_chunk(values: &mut Self::Values, block: usize) -> Self::Chunk {
+        &values.generation[block * 256..(block + 1) * 256]
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::*;
@@ -125,6 +165,9 @@ mod tests {
         assert!(entities.is_alive(e2));
         assert!(entities.is_alive(e3));
         assert!(entities.is_alive(e4));
+        let iter = entities.iter_with_bitset(entities.bitset().clone());
+        assert_eq!(iter.len(), 3);
+        assert_eq!(iter.rev().flatten().collect::<Vec<_>>(), vec![e4, e3, e2]);
 
         assert_eq!(*entities.killed(), vec![e1]);
         entities.clear_killed();
@@ -141,8 +184,8 @@ mod tests {
         assert_eq!(e2.index(), 1);
         entities.kill(e1);
         entities.kill(e2);
-        assert_eq!(entities.is_alive(e1), false);
-        assert_eq!(entities.is_alive(e2), false);
+        assert!(!entities.is_alive(e1));
+        assert!(!entities.is_alive(e2));
 
         let e3 = entities.create();
         assert_eq!(e3.index(), 2);
@@ -150,7 +193,7 @@ mod tests {
         assert_eq!(e4.index(), 3);
         entities.kill(e3);
         entities.kill(e4);
-        assert_eq!(entities.is_alive(e3), false);
-        assert_eq!(entities.is_alive(e4), false);
+        assert!(!entities.is_alive(e3));
+        assert!(!entities.is_alive(e4));
     }
 }
@@ -1,34 +1,45 @@
-use crate::{BitSet, BitSetVec, Entity};
+use crate::{BitSetCursor, Entity, LayeredBitSet};
+
+use std::iter::FusedIterator;
 
 /// Iterator over entities using the provided bitset.
+///
+/// One item is returned per bit set, so the exact length is known upfront.
 pub struct EntityIterator<'a> {
-    pub(crate) current_id: usize,
+    pub(crate) cursor: BitSetCursor,
     pub(crate) next_id: usize,
-    pub(crate) entities: &'a BitSetVec,
+    pub(crate) entities: &'a LayeredBitSet,
     pub(crate) generations: &'a Vec<u32>,
-    //pub(crate) bitset: &'a BitSetVec,
-    pub(crate) bitset: std::rc::Rc<BitSetVec>,
+}
+
+impl<'a> EntityIterator<'a> {
+    fn fetch(&self, id: usize) -> Option<Entity> {
+        if id < self.next_id && self.entities.bit_test(id) {
+            Some(Entity::new(id as u32, self.generations[id]))
+        } else {
+            None
+        }
+    }
 }
 
 impl<'a> Iterator for EntityIterator<'a> {
     type Item = Option<Entity>;
     fn next(&mut self) -> Option<Self::Item> {
-        while !self.bitset.bit_test(self.current_id) && self.current_id < self.next_id {
-            self.current_id += 1;
-        }
-        let ret = if self.current_id < self.next_id {
-            if self.entities.bit_test(self.current_id) {
-                Some(Some(Entity::new(
-                    self.current_id as u32,
-                    self.generations[self.current_id],
-                )))
-            } else {
-                Some(None)
-            }
-        } else {
-            None
-        };
-        self.current_id += 1;
-        ret
+        let id = self.cursor.next_front()?;
+        Some(self.fetch(id))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.cursor.remaining(), Some(self.cursor.remaining()))
+    }
+}
+
+impl<'a> DoubleEndedIterator for EntityIterator<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let id = self.cursor.next_back()?;
+        Some(self.fetch(id))
     }
 }
+
+impl<'a> ExactSizeIterator for EntityIterator<'a> {}
+
+impl<'a> FusedIterator for EntityIterator<'a> {}
@@ -0,0 +1,279 @@
+use crate::{Entities, Entity, LayeredBitSet, BITSET_SIZE};
+
+use std::iter::FromIterator;
+use std::ops::{BitAnd, BitOr, BitXor, Not, Sub};
+use std::rc::Rc;
+
+/// A set of entity indices, usable to build filters for joins.
+///
+/// Sets can be combined using `&` (intersection), `|` (union),
+/// `^` (symmetric difference), `-` (difference) and `!` (complement).
+/// Complements are stored lazily, so `a & !b` never allocates a bitset
+/// covering all possible entities.
+///
+/// An `EntitySet` can be used in place of a bitset in `iter_with_bitset`.
+#[derive(Clone, Debug, Default)]
+pub struct EntitySet {
+    bits: LayeredBitSet,
+    /// When true, the set contains every index that is not in `bits`.
+    inverted: bool,
+}
+
+impl EntitySet {
+    /// Creates an empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds the index of `entity` to the set.
+    pub fn insert(&mut self, entity: Entity) {
+        if self.inverted {
+            self.bits.bit_reset(entity.index() as usize);
+        } else {
+            self.bits.bit_set(entity.index() as usize);
+        }
+    }
+    /// Removes the index of `entity` from the set.
+    pub fn remove(&mut self, entity: Entity) {
+        if self.inverted {
+            self.bits.bit_set(entity.index() as usize);
+        } else {
+            self.bits.bit_reset(entity.index() as usize);
+        }
+    }
+    /// Returns true if the index of `entity` is in the set.
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.bits.bit_test(entity.index() as usize) != self.inverted
+    }
+    /// Returns the amount of indices in the set.
+    pub fn count(&self) -> usize {
+        if self.inverted {
+            BITSET_SIZE - self.bits.bit_count()
+        } else {
+            self.bits.bit_count()
+        }
+    }
+    /// Returns true if the set contains no index.
+    pub fn is_empty(&self) -> bool {
+        self.count() == 0
+    }
+    /// Iterates over the indices in the set, in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        let mut current = 0;
+        std::iter::from_fn(move || {
+            let next = if self.inverted {
+                (current..BITSET_SIZE).find(|i| !self.bits.bit_test(*i))
+            } else {
+                self.bits.next_set(current)
+            }?;
+            current = next + 1;
+            Some(next as u32)
+        })
+    }
+    /// Iterates over the alive entities whose index is in the set, in
+    /// increasing order. Useful to drive `Join::join_subset`.
+    pub fn entities<'a>(&'a self, entities: &'a Entities) -> impl Iterator<Item = Entity> + 'a {
+        let high = entities.bitset().high_water();
+        self.iter()
+            .take_while(move |i| (*i as usize) < high)
+            .filter_map(move |i| entities.entity(i))
+    }
+    /// Returns the set as a bitset.
+    /// Complemented sets are expanded to the maximum amount of entities.
+    pub fn to_bitset(&self) -> LayeredBitSet {
+        if self.inverted {
+            let mut bits = self.bits.bounded(BITSET_SIZE);
+            bits.bit_not();
+            bits
+        } else {
+            self.bits.clone()
+        }
+    }
+    fn and(mut self, rhs: &EntitySet) -> EntitySet {
+        match (self.inverted, rhs.inverted) {
+            (false, false) => {
+                self.bits.bit_and(&rhs.bits);
+            }
+            (false, true) => {
+                self.bits.bit_andnot(&rhs.bits);
+            }
+            (true, false) => {
+                let mut bits = rhs.bits.clone();
+                bits.bit_andnot(&self.bits);
+                return EntitySet::from(bits);
+            }
+            // !a & !b == !(a | b)
+            (true, true) => {
+                self.bits.bit_or(&rhs.bits);
+            }
+        }
+        self
+    }
+    fn or(mut self, rhs: &EntitySet) -> EntitySet {
+        match (self.inverted, rhs.inverted) {
+            (false, false) => {
+                self.bits.bit_or(&rhs.bits);
+            }
+            // a | !b == !(b - a)
+            (false, true) => {
+                let mut bits = rhs.bits.clone();
+                bits.bit_andnot(&self.bits);
+                return !EntitySet::from(bits);
+            }
+            (true, false) => {
+                self.bits.bit_andnot(&rhs.bits);
+            }
+            // !a | !b == !(a & b)
+            (true, true) => {
+                self.bits.bit_and(&rhs.bits);
+            }
+        }
+        self
+    }
+    fn difference(self, rhs: &EntitySet) -> EntitySet {
+        self.and(&!rhs)
+    }
+    fn xor(mut self, rhs: &EntitySet) -> EntitySet {
+        // Complements cancel each other out.
+        self.bits.bit_xor(&rhs.bits);
+        self.inverted ^= rhs.inverted;
+        self
+    }
+}
+
+/// Sets are equal when they contain the same indices, whether they are
+/// stored as complements or not.
+impl PartialEq for EntitySet {
+    fn eq(&self, other: &Self) -> bool {
+        if self.inverted == other.inverted {
+            self.bits == other.bits
+        } else {
+            self.to_bitset() == other.to_bitset()
+        }
+    }
+}
+
+impl Eq for EntitySet {}
+
+impl From<LayeredBitSet> for EntitySet {
+    fn from(bits: LayeredBitSet) -> Self {
+        EntitySet {
+            bits,
+            inverted: false,
+        }
+    }
+}
+
+impl From<&LayeredBitSet> for EntitySet {
+    fn from(bits: &LayeredBitSet) -> Self {
+        EntitySet::from(bits.clone())
+    }
+}
+
+impl From<EntitySet> for Rc<LayeredBitSet> {
+    fn from(set: EntitySet) -> Self {
+        if set.inverted {
+            Rc::new(set.to_bitset())
+        } else {
+            Rc::new(set.bits)
+        }
+    }
+}
+
+impl FromIterator<Entity> for EntitySet {
+    fn from_iter<I: IntoIterator<Item = Entity>>(iter: I) -> Self {
+        let mut set = EntitySet::new();
+        for e in iter {
+            set.insert(e);
+        }
+        set
+    }
+}
+
+macro_rules! impl_set_op {
+    ($op:ident, $fn:ident, $method:ident) => {
+        impl $op<EntitySet> for EntitySet {
+            type Output = EntitySet;
+            fn $fn(self, rhs: EntitySet) -> EntitySet {
+                self.$method(&rhs)
+            }
+        }
+        impl<'a> $op<&'a EntitySet> for EntitySet {
+            type Output = EntitySet;
+            fn $fn(self, rhs: &'a EntitySet) -> EntitySet {
+                self.$method(rhs)
+            }
+        }
+        impl<'a> $op<EntitySet> for &'a EntitySet {
+            type Output = EntitySet;
+            fn $fn(self, rhs: EntitySet) -> EntitySet {
+                self.clone().$method(&rhs)
+            }
+        }
+        impl<'a, 'b> $op<&'b EntitySet> for &'a EntitySet {
+            type Output = EntitySet;
+            fn $fn(self, rhs: &'b EntitySet) -> EntitySet {
+                self.clone().$method(rhs)
+            }
+        }
+    };
+}
+
+impl_set_op!(BitAnd, bitand, and);
+impl_set_op!(BitOr, bitor, or);
+impl_set_op!(BitXor, bitxor, xor);
+impl_set_op!(Sub, sub, difference);
+
+
+impl Not for EntitySet {
+    type Output = EntitySet;
+    fn not(mut self) -> EntitySet {
+        self.inverted = !self.inverted;
+        self
+    }
+}
+
+impl Not for &EntitySet {
+    type Output = EntitySet;
+    fn not(self) -> EntitySet {
+        !self.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    #[test]
+    fn set_operators() {
+        struct A;
+        let mut entities = Entities::default();
+        let e = (0..6).map(|_| entities.create()).collect::<Vec<_>>();
+        let a = e[0..4].iter().copied().collect::<EntitySet>();
+        let b = e[2..6].iter().copied().collect::<EntitySet>();
+
+        assert_eq!((&a & &b).iter().collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!((&a | &b).count(), 6);
+        assert_eq!((&a ^ &b).iter().collect::<Vec<_>>(), vec![0, 1, 4, 5]);
+        assert_eq!((&a - &b).iter().collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(&a - b.clone(), a.clone() - &b);
+        assert_eq!((&b & &!&a).iter().collect::<Vec<_>>(), vec![4, 5]);
+        assert_eq!((!&a).count(), BITSET_SIZE - 4);
+        assert!((!&a).contains(e[5]));
+        assert_eq!((!&a | &b).iter().take(3).collect::<Vec<_>>(), vec![2, 3, 4]);
+        assert_eq!(!(!&a & !&b), &a | &b);
+        assert!((&a & !&a).is_empty());
+
+        let mut all = LayeredBitSet::default().bounded(BITSET_SIZE);
+        all.bit_not();
+        assert_eq!(EntitySet::from(&all), !EntitySet::new());
+        all.bit_reset(3);
+        assert_ne!(EntitySet::from(&all), !EntitySet::new());
+        assert_eq!(EntitySet::from(all), !std::iter::once(e[3]).collect::<EntitySet>());
+
+        let mut storage = Components::<A>::default();
+        for e in e.iter() {
+            storage.insert(*e, A);
+        }
+        let filter = EntitySet::from(storage.bitset()) - a;
+        assert_eq!(entities.iter_with_bitset(filter).count(), 2);
+    }
+}
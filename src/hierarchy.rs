@@ -0,0 +1,164 @@
+use crate::{register_cleanup, Components, Entities, Entity};
+
+/// The parent of an `Entity` in a `Hierarchy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Parent(pub Entity);
+
+/// The children of an `Entity` in a `Hierarchy`, in insertion order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Children(pub Vec<Entity>);
+
+/// Parent-children relations between entities, kept consistent in both
+/// directions.
+///
+/// The `Parent` and `Children` storages can be borrowed to join over.
+/// Killed entities are removed by the killed entities cleanup, which turns
+/// their children into roots. Use `kill_recursive` to kill a whole subtree
+/// instead.
+pub struct Hierarchy {
+    parents: Components<Parent>,
+    children: Components<Children>,
+}
+
+impl Default for Hierarchy {
+    fn default() -> Self {
+        register_cleanup::<Self>(|me, e| {
+            me.remove(e);
+        });
+        Self {
+            parents: Components::default(),
+            children: Components::default(),
+        }
+    }
+}
+
+impl Hierarchy {
+    /// Makes `parent` the parent of `child`, detaching it from its previous
+    /// parent. `child` is added last in the children of `parent`.
+    ///
+    /// Panics if `parent` is `child` or one of its descendants.
+    pub fn set_parent(&mut self, child: Entity, parent: Entity) {
+        assert!(
+            parent != child && !self.ancestors(parent).any(|a| a == child),
+            "Setting this parent would create a cycle in the hierarchy."
+        );
+        self.remove_parent(child);
+        self.parents.insert(child, Parent(parent));
+        if let Some(children) = self.children.get_mut(parent) {
+            children.0.push(child);
+        } else {
+            self.children.insert(parent, Children(vec![child]));
+        }
+    }
+    /// Detaches `child` from its parent, making it a root.
+    /// Returns the previous parent, if any.
+    pub fn remove_parent(&mut self, child: Entity) -> Option<Entity> {
+        let parent = self.parents.remove(child)?.0;
+        let children = self.children.get_mut(parent).unwrap();
+        children.0.retain(|c| *c != child);
+        if children.0.is_empty() {
+            self.children.remove(parent);
+        }
+        Some(parent)
+    }
+    /// Removes `entity` from the hierarchy. Its children become roots.
+    pub fn remove(&mut self, entity: Entity) {
+        self.remove_parent(entity);
+        if let Some(children) = self.children.remove(entity) {
+            for c in children.0 {
+                self.parents.remove(c);
+            }
+        }
+    }
+    /// Returns the parent of `entity`, if any.
+    pub fn parent(&self, entity: Entity) -> Option<Entity> {
+        self.parents.get(entity).map(|p| p.0)
+    }
+    /// Returns the children of `entity`.
+    pub fn children(&self, entity: Entity) -> &[Entity] {
+        self.children.get(entity).map(|c| &c.0[..]).unwrap_or(&[])
+    }
+    /// Iterates over the parent of `entity`, then its grandparent and so on
+    /// up to the root.
+    pub fn ancestors(&self, entity: Entity) -> impl Iterator<Item = Entity> + '_ {
+        let mut current = entity;
+        std::iter::from_fn(move || {
+            current = self.parent(current)?;
+            Some(current)
+        })
+    }
+    /// Iterates over all the descendants of `entity` in depth-first order,
+    /// each parent coming before its children.
+    pub fn descendants(&self, entity: Entity) -> impl Iterator<Item = Entity> + '_ {
+        let mut stack = self.children(entity).iter().rev().copied().collect::<Vec<_>>();
+        std::iter::from_fn(move || {
+            let next = stack.pop()?;
+            stack.extend(self.children(next).iter().rev());
+            Some(next)
+        })
+    }
+    /// Kills `entity` and all of its descendants, removing them from the
+    /// hierarchy. They are all added to the killed entities of `entities`,
+    /// so that their other components get cleaned up.
+    pub fn kill_recursive(&mut self, entities: &mut Entities, entity: Entity) {
+        let subtree = std::iter::once(entity).chain(self.descendants(entity)).collect::<Vec<_>>();
+        self.remove_parent(entity);
+        for e in subtree {
+            self.parents.remove(e);
+            self.children.remove(e);
+            entities.kill(e);
+        }
+    }
+    /// Returns the storage of the parents, to join over.
+    pub fn parents_storage(&self) -> &Components<Parent> {
+        &self.parents
+    }
+    /// Returns the storage of the children, to join over.
+    pub fn children_storage(&self) -> &Components<Children> {
+        &self.children
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    #[test]
+    fn hierarchy_consistency() {
+        let mut entities = Entities::default();
+        let mut hierarchy = Hierarchy::default();
+        let e = (0..6).map(|_| entities.create()).collect::<Vec<_>>();
+        // 0 -> (1 -> (3, 4), 2), 5 alone.
+        hierarchy.set_parent(e[1], e[0]);
+        hierarchy.set_parent(e[2], e[0]);
+        hierarchy.set_parent(e[3], e[1]);
+        hierarchy.set_parent(e[4], e[1]);
+        assert_eq!(hierarchy.children(e[0]), &[e[1], e[2]]);
+        assert_eq!(hierarchy.ancestors(e[4]).collect::<Vec<_>>(), vec![e[1], e[0]]);
+        assert_eq!(hierarchy.descendants(e[0]).collect::<Vec<_>>(), vec![e[1], e[3], e[4], e[2]]);
+        assert_eq!((&entities, hierarchy.parents_storage()).join().count(), 4);
+
+        hierarchy.set_parent(e[4], e[5]);
+        assert_eq!(hierarchy.children(e[1]), &[e[3]]);
+        assert_eq!(hierarchy.parent(e[4]), Some(e[5]));
+
+        hierarchy.kill_recursive(&mut entities, e[1]);
+        assert_eq!(*entities.killed(), vec![e[1], e[3]]);
+        assert_eq!(hierarchy.children(e[0]), &[e[2]]);
+        assert_eq!(hierarchy.parent(e[3]), None);
+
+        hierarchy.remove(e[5]);
+        assert_eq!(hierarchy.parent(e[4]), None);
+        assert!(hierarchy.children(e[5]).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "cycle")]
+    fn hierarchy_cycle() {
+        let mut entities = Entities::default();
+        let mut hierarchy = Hierarchy::default();
+        let a = entities.create();
+        let b = entities.create();
+        hierarchy.set_parent(b, a);
+        hierarchy.set_parent(a, b);
+    }
+}
@@ -0,0 +1,299 @@
+use crate::{register_cleanup, ComponentIterator, Components, Entity, LayeredBitSet};
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::ops::{Deref, DerefMut};
+
+/// A reverse index from keys to the entities holding them.
+/// Implemented by `UniqueIndex<K>` for unique keys and by
+/// `HashMap<K, Vec<Entity>>` for keys shared by multiple entities.
+pub trait ComponentIndex<K>: Default {
+    /// Associates `key` with `entity`.
+    fn add(&mut self, key: K, entity: Entity);
+    /// Removes the association between `key` and `entity`.
+    fn remove(&mut self, key: &K, entity: Entity);
+}
+
+/// A reverse index from keys to a single entity.
+///
+/// When another entity gets a key already in use, it takes over the key and
+/// the previous entity is kept aside. Removing the new entity then gives the
+/// key back to the previous one, so the index never loses track of an entity
+/// still holding the key.
+pub struct UniqueIndex<K> {
+    entities: HashMap<K, Entity>,
+    /// Entities that were taken over, in the order they were.
+    shadowed: Vec<(K, Entity)>,
+}
+
+impl<K> Default for UniqueIndex<K> {
+    fn default() -> Self {
+        UniqueIndex {
+            entities: HashMap::default(),
+            shadowed: vec![],
+        }
+    }
+}
+
+impl<K: Hash + Eq> UniqueIndex<K> {
+    /// Returns the `Entity` holding `key`.
+    pub fn get<Q: Hash + Eq + ?Sized>(&self, key: &Q) -> Option<Entity>
+    where
+        K: Borrow<Q>,
+    {
+        self.entities.get(key).copied()
+    }
+}
+
+impl<K: Hash + Eq> ComponentIndex<K> for UniqueIndex<K> {
+    fn add(&mut self, key: K, entity: Entity) {
+        if let Some(current) = self.entities.get_mut(&key) {
+            let previous = std::mem::replace(current, entity);
+            self.shadowed.push((key, previous));
+        } else {
+            self.entities.insert(key, entity);
+        }
+    }
+    fn remove(&mut self, key: &K, entity: Entity) {
+        if self.entities.get(key) == Some(&entity) {
+            // Give the key back to the last entity that was taken over.
+            if let Some(pos) = self.shadowed.iter().rposition(|(k, _)| k == key) {
+                let (_, previous) = self.shadowed.remove(pos);
+                *self.entities.get_mut(key).unwrap() = previous;
+            } else {
+                self.entities.remove(key);
+            }
+        } else if let Some(pos) = self.shadowed.iter().position(|(k, e)| k == key && *e == entity) {
+            self.shadowed.remove(pos);
+        }
+    }
+}
+
+impl<K: Hash + Eq> ComponentIndex<K> for HashMap<K, Vec<Entity>> {
+    fn add(&mut self, key: K, entity: Entity) {
+        self.entry(key).or_default().push(entity);
+    }
+    fn remove(&mut self, key: &K, entity: Entity) {
+        if let Some(entities) = self.get_mut(key) {
+            entities.retain(|e| *e != entity);
+            if entities.is_empty() {
+                HashMap::remove(self, key);
+            }
+        }
+    }
+}
+
+/// Holds components of a given type indexed by `Entity`, along with a reverse
+/// index from a key computed from each component to the entities holding it.
+///
+/// The index is kept up to date by `insert`, `remove` and writes made through
+/// `get_mut`. Mutable iteration is not provided since it would bypass the index.
+pub struct IndexedComponents<T, K, I> {
+    components: Components<T>,
+    key: fn(&T) -> K,
+    index: I,
+}
+
+/// Components indexed by a key that is unique to each entity.
+/// If two entities share the same key, the last one to get it is returned by
+/// `lookup`, and the other one is returned again once it loses the key.
+pub type UniqueIndexed<T, K> = IndexedComponents<T, K, UniqueIndex<K>>;
+/// Components indexed by a key that can be shared by multiple entities.
+pub type MultiIndexed<T, K> = IndexedComponents<T, K, HashMap<K, Vec<Entity>>>;
+
+impl<T: 'static, K: Hash + Eq + 'static, I: ComponentIndex<K> + 'static> IndexedComponents<T, K, I> {
+    /// Creates an empty storage indexed by the value returned by `key`.
+    pub fn new(key: fn(&T) -> K) -> Self {
+        register_cleanup::<Self>(|me, e| {
+            me.remove(e);
+        });
+        Self {
+            components: Components::default(),
+            key,
+            index: I::default(),
+        }
+    }
+}
+
+impl<T, K: Hash + Eq, I: ComponentIndex<K>> IndexedComponents<T, K, I> {
+    /// Inserts a component for the given `Entity` index.
+    /// Returns the previous component, if any.
+    pub fn insert(&mut self, entity: Entity, component: T) -> Option<T> {
+        let key = (self.key)(&component);
+        let old = self.components.insert(entity, component);
+        if let Some(old) = old.as_ref() {
+            self.index.remove(&(self.key)(old), entity);
+        }
+        self.index.add(key, entity);
+        old
+    }
+    /// Gets an immutable reference to the component of `Entity`.
+    pub fn get(&self, entity: Entity) -> Option<&T> {
+        self.components.get(entity)
+    }
+    /// Gets a mutable reference to the component of `Entity`.
+    /// The index is updated when the returned guard is dropped.
+    pub fn get_mut(&mut self, entity: Entity) -> Option<IndexedMut<'_, T, K, I>> {
+        let key = self.key;
+        let component = self.components.get_mut(entity)?;
+        Some(IndexedMut {
+            old_key: Some(key(component)),
+            key,
+            component,
+            index: &mut self.index,
+            entity,
+        })
+    }
+    /// Removes the component of `Entity`.
+    /// Returns `Some(T)` if the entity did have the component.
+    /// Returns `None` if the entity did not have the component.
+    pub fn remove(&mut self, entity: Entity) -> Option<T> {
+        let removed = self.components.remove(entity);
+        if let Some(removed) = removed.as_ref() {
+            self.index.remove(&(self.key)(removed), entity);
+        }
+        removed
+    }
+    /// Iterates immutably over all components of this type.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.components.iter()
+    }
+    /// Iterates immutably over the components of this type where `bitset`
+    /// indicates the indices of entities.
+    pub fn iter_with_bitset(&self, bitset: impl Into<std::rc::Rc<LayeredBitSet>>) -> ComponentIterator<'_, T> {
+        self.components.iter_with_bitset(bitset)
+    }
+    /// Returns the bitset indicating which entity indices have a component
+    /// associated to them.
+    pub fn bitset(&self) -> &LayeredBitSet {
+        self.components.bitset()
+    }
+    /// Returns the underlying `Components`.
+    pub fn components(&self) -> &Components<T> {
+        &self.components
+    }
+}
+
+impl<T, K: Hash + Eq> IndexedComponents<T, K, UniqueIndex<K>> {
+    /// Returns the `Entity` whose component has the given key.
+    pub fn lookup<Q: Hash + Eq + ?Sized>(&self, key: &Q) -> Option<Entity>
+    where
+        K: Borrow<Q>,
+    {
+        self.index.get(key)
+    }
+}
+
+impl<T, K: Hash + Eq> IndexedComponents<T, K, HashMap<K, Vec<Entity>>> {
+    /// Returns all entities whose component has the given key.
+    pub fn lookup<Q: Hash + Eq + ?Sized>(&self, key: &Q) -> &[Entity]
+    where
+        K: Borrow<Q>,
+    {
+        self.index.get(key).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+}
+
+/// A mutable reference to a component of an `IndexedComponents`.
+/// Updates the index when dropped if the key of the component changed.
+pub struct IndexedMut<'a, T, K: Hash + Eq, I: ComponentIndex<K>> {
+    old_key: Option<K>,
+    key: fn(&T) -> K,
+    component: &'a mut T,
+    index: &'a mut I,
+    entity: Entity,
+}
+
+impl<'a, T, K: Hash + Eq, I: ComponentIndex<K>> Deref for IndexedMut<'a, T, K, I> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.component
+    }
+}
+
+impl<'a, T, K: Hash + Eq, I: ComponentIndex<K>> DerefMut for IndexedMut<'a, T, K, I> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.component
+    }
+}
+
+impl<'a, T, K: Hash + Eq, I: ComponentIndex<K>> Drop for IndexedMut<'a, T, K, I> {
+    fn drop(&mut self) {
+        let old = self.old_key.take().unwrap();
+        let new = (self.key)(self.component);
+        if old != new {
+            self.index.remove(&old, self.entity);
+            self.index.add(new, self.entity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    #[test]
+    fn unique_lookup() {
+        struct NetworkId(u32);
+
+        let mut entities = Entities::default();
+        let e1 = entities.create();
+        let e2 = entities.create();
+
+        let mut storage = UniqueIndexed::<NetworkId, u32>::new(|n| n.0);
+        storage.insert(e1, NetworkId(42));
+        storage.insert(e2, NetworkId(7));
+        assert_eq!(storage.lookup(&42), Some(e1));
+        assert_eq!(storage.lookup(&7), Some(e2));
+
+        storage.get_mut(e1).unwrap().0 = 43;
+        assert_eq!(storage.lookup(&42), None);
+        assert_eq!(storage.lookup(&43), Some(e1));
+
+        storage.insert(e2, NetworkId(8));
+        assert_eq!(storage.lookup(&7), None);
+        assert_eq!(storage.lookup(&8), Some(e2));
+
+        storage.remove(e1);
+        assert_eq!(storage.lookup(&43), None);
+
+        // A colliding key shadows the previous entity until it is removed.
+        let e3 = entities.create();
+        storage.insert(e1, NetworkId(42));
+        storage.insert(e2, NetworkId(42));
+        storage.insert(e3, NetworkId(42));
+        assert_eq!(storage.lookup(&42), Some(e3));
+        storage.remove(e2);
+        assert_eq!(storage.lookup(&42), Some(e3));
+        storage.get_mut(e3).unwrap().0 = 9;
+        assert_eq!(storage.lookup(&42), Some(e1));
+        storage.remove(e1);
+        assert_eq!(storage.lookup(&42), None);
+        assert_eq!(storage.lookup(&9), Some(e3));
+    }
+
+    #[test]
+    fn multi_lookup() {
+        struct Name(String);
+
+        let mut entities = Entities::default();
+        let e1 = entities.create();
+        let e2 = entities.create();
+
+        let mut storage = MultiIndexed::<Name, String>::new(|n| n.0.clone());
+        storage.insert(e1, Name("boss".to_string()));
+        storage.insert(e2, Name("boss".to_string()));
+        assert_eq!(storage.lookup("boss"), &[e1, e2]);
+
+        storage.insert(e1, Name("boss".to_string()));
+        assert_eq!(storage.lookup("boss").len(), 2);
+
+        storage.get_mut(e2).unwrap().0 = "minion".to_string();
+        assert_eq!(storage.lookup("boss"), &[e1]);
+        assert_eq!(storage.lookup("minion"), &[e2]);
+
+        storage.remove(e1);
+        assert!(storage.lookup("boss").is_empty());
+        assert_eq!(join!(&storage).count(), 1);
+    }
+}
@@ -1,89 +1,329 @@
+use crate::{Entities, Entity, LayeredBitSet};
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! bitset_bound {
+    ($bound:expr;) => { $bound };
+    ($bound:expr; &mut $st:ident $($tail:tt)*) => {
+        bitset_bound!($bound.max($st.bitset().high_water()); $($tail)*)
+    };
+    ($bound:expr; &$st:ident $($tail:tt)*) => {
+        bitset_bound!($bound.max($st.bitset().high_water()); $($tail)*)
+    };
+    ($bound:expr; !&$st:ident $($tail:tt)*) => {
+        bitset_bound!($bound.max($st.bitset().high_water()); $($tail)*)
+    };
+    ($bound:expr; maybe &mut $st:ident $($tail:tt)*) => {
+        bitset_bound!($bound.max($st.bitset().high_water()); $($tail)*)
+    };
+    ($bound:expr; maybe &$st:ident $($tail:tt)*) => {
+        bitset_bound!($bound.max($st.bitset().high_water()); $($tail)*)
+    };
+    ($bound:expr; && $($tail:tt)*) => {
+        bitset_bound!($bound; $($tail)*)
+    };
+    ($bound:expr; || $($tail:tt)*) => {
+        bitset_bound!($bound; $($tail)*)
+    };
+    ($bound:expr; !($($inner:tt)*) $($tail:tt)*) => {
+        bitset_bound!($bound; $($inner)* $($tail)*)
+    };
+    ($bound:expr; ($($inner:tt)*) $($tail:tt)*) => {
+        bitset_bound!($bound; $($inner)* $($tail)*)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! bitset_versions {
+    ($(,)?$($versions:expr),* ;) => { [$($versions),*] };
+    ($(,)?$($versions:expr),* ; &mut $st:ident $($tail:tt)*) => {
+        bitset_versions!($($versions),* , $st.bitset().version() ; $($tail)*)
+    };
+    ($(,)?$($versions:expr),* ; &$st:ident $($tail:tt)*) => {
+        bitset_versions!($($versions),* , $st.bitset().version() ; $($tail)*)
+    };
+    ($(,)?$($versions:expr),* ; !&$st:ident $($tail:tt)*) => {
+        bitset_versions!($($versions),* , $st.bitset().version() ; $($tail)*)
+    };
+    ($(,)?$($versions:expr),* ; maybe &mut $st:ident $($tail:tt)*) => {
+        bitset_versions!($($versions),* , $st.bitset().version() ; $($tail)*)
+    };
+    ($(,)?$($versions:expr),* ; maybe &$st:ident $($tail:tt)*) => {
+        bitset_versions!($($versions),* , $st.bitset().version() ; $($tail)*)
+    };
+    ($(,)?$($versions:expr),* ; && $($tail:tt)*) => {
+        bitset_versions!($($versions),* ; $($tail)*)
+    };
+    ($(,)?$($versions:expr),* ; || $($tail:tt)*) => {
+        bitset_versions!($($versions),* ; $($tail)*)
+    };
+    ($(,)?$($versions:expr),* ; !($($inner:tt)*) $($tail:tt)*) => {
+        bitset_versions!($($versions),* ; $($inner)* $($tail)*)
+    };
+    ($(,)?$($versions:expr),* ; ($($inner:tt)*) $($tail:tt)*) => {
+        bitset_versions!($($versions),* ; $($inner)* $($tail)*)
+    };
+}
+
+/// Computes the bitset of a join expression into `$bitset`.
+///
+/// `||` binds looser than `&&`, so the expression is first split into the
+/// terms of an `||` chain, each of them being a chain of `&&` factors.
+/// Parenthesized groups are evaluated into a temporary bitset.
 #[doc(hidden)]
 #[macro_export]
 macro_rules! gen_bitset {
-    ($bitset:ident;) => {};
-    ($bitset:ident; &mut $st:ident $($tail:tt)*) => {
-        *std::rc::Rc::get_mut(&mut $bitset).unwrap() = $st.bitset().clone();
-        gen_bitset!($bitset; $($tail)*);
+    // Splits on the top level `||`.
+    (@or $dst:ident, $bound:ident; $pos:ident; [$($cur:tt)*]) => {
+        gen_bitset!(@term $dst, $bound; $pos; $($cur)*);
+    };
+    (@or $dst:ident, $bound:ident; $pos:ident; [$($cur:tt)*] || $($tail:tt)*) => {
+        gen_bitset!(@term $dst, $bound; $pos; $($cur)*);
+        gen_bitset!(@or $dst, $bound; rest; [] $($tail)*);
+    };
+    (@or $dst:ident, $bound:ident; $pos:ident; [$($cur:tt)*] $next:tt $($tail:tt)*) => {
+        gen_bitset!(@or $dst, $bound; $pos; [$($cur)* $next] $($tail)*);
+    };
+    // A term of the `||` chain. The first one is computed in place.
+    (@term $dst:ident, $bound:ident; first; $($and:tt)*) => {
+        gen_bitset!(@and $dst, $bound; $($and)*);
+    };
+    (@term $dst:ident, $bound:ident; rest; &mut $st:ident) => {
+        $dst.bit_or($st.bitset());
+    };
+    (@term $dst:ident, $bound:ident; rest; &$st:ident) => {
+        $dst.bit_or($st.bitset());
+    };
+    (@term $dst:ident, $bound:ident; rest; $($and:tt)*) => {
+        {
+            let mut term = $crate::LayeredBitSet::default();
+            gen_bitset!(@and term, $bound; $($and)*);
+            $dst.bit_or(&term);
+        }
+    };
+    // Plans a `&&` chain: the plain storages are intersected first, starting
+    // from the one with the fewest components, then the other factors are
+    // applied in order. The result is the same in any order.
+    (@and $dst:ident, $bound:ident; $($and:tt)*) => {
+        gen_bitset!(@plan $dst, $bound; [] [] [$($and)*] $($and)*);
+    };
+    (@plan $dst:ident, $bound:ident; [] [$($o:tt)*] [$($and:tt)*]) => {
+        gen_bitset!(@first $dst, $bound; $($and)*);
+    };
+    (@plan $dst:ident, $bound:ident; [$($p:ident)+] [$($o:tt)*] [$($and:tt)*]) => {
+        {
+            let mut plain = [$($p.bitset()),+];
+            plain.sort_by_key(|bitset| bitset.bit_count());
+            $dst.copy_bounded(plain[0], $bound);
+            for bitset in &plain[1..] {
+                $dst.bit_and(bitset);
+            }
+        }
+        gen_bitset!(@and_rest $dst, $bound; $($o)*);
+    };
+    (@plan $dst:ident, $bound:ident; [$($p:ident)*] [$($o:tt)*] [$($and:tt)*] && $($tail:tt)*) => {
+        gen_bitset!(@plan $dst, $bound; [$($p)*] [$($o)*] [$($and)*] $($tail)*);
+    };
+    (@plan $dst:ident, $bound:ident; [$($p:ident)*] [$($o:tt)*] [$($and:tt)*] &mut $st:ident $($tail:tt)*) => {
+        gen_bitset!(@plan $dst, $bound; [$($p)* $st] [$($o)*] [$($and)*] $($tail)*);
+    };
+    (@plan $dst:ident, $bound:ident; [$($p:ident)*] [$($o:tt)*] [$($and:tt)*] &$st:ident $($tail:tt)*) => {
+        gen_bitset!(@plan $dst, $bound; [$($p)* $st] [$($o)*] [$($and)*] $($tail)*);
+    };
+    (@plan $dst:ident, $bound:ident; [$($p:ident)*] [$($o:tt)*] [$($and:tt)*] !&$st:ident $($tail:tt)*) => {
+        gen_bitset!(@plan $dst, $bound; [$($p)*] [$($o)* && !&$st] [$($and)*] $($tail)*);
+    };
+    (@plan $dst:ident, $bound:ident; [$($p:ident)*] [$($o:tt)*] [$($and:tt)*] maybe &mut $st:ident $($tail:tt)*) => {
+        gen_bitset!(@plan $dst, $bound; [$($p)*] [$($o)* && maybe &mut $st] [$($and)*] $($tail)*);
+    };
+    (@plan $dst:ident, $bound:ident; [$($p:ident)*] [$($o:tt)*] [$($and:tt)*] maybe &$st:ident $($tail:tt)*) => {
+        gen_bitset!(@plan $dst, $bound; [$($p)*] [$($o)* && maybe &$st] [$($and)*] $($tail)*);
+    };
+    (@plan $dst:ident, $bound:ident; [$($p:ident)*] [$($o:tt)*] [$($and:tt)*] !($($inner:tt)*) $($tail:tt)*) => {
+        gen_bitset!(@plan $dst, $bound; [$($p)*] [$($o)* && !($($inner)*)] [$($and)*] $($tail)*);
+    };
+    (@plan $dst:ident, $bound:ident; [$($p:ident)*] [$($o:tt)*] [$($and:tt)*] ($($inner:tt)*) $($tail:tt)*) => {
+        gen_bitset!(@plan $dst, $bound; [$($p)*] [$($o)* && ($($inner)*)] [$($and)*] $($tail)*);
+    };
+    // The first factor of a `&&` chain without plain storages.
+    (@first $dst:ident, $bound:ident; &mut $st:ident $($tail:tt)*) => {
+        $dst.copy_bounded($st.bitset(), $bound);
+        gen_bitset!(@and_rest $dst, $bound; $($tail)*);
+    };
+    (@first $dst:ident, $bound:ident; &$st:ident $($tail:tt)*) => {
+        $dst.copy_bounded($st.bitset(), $bound);
+        gen_bitset!(@and_rest $dst, $bound; $($tail)*);
+    };
+    (@first $dst:ident, $bound:ident; !&$st:ident $($tail:tt)*) => {
+        $dst.copy_bounded($st.bitset(), $bound).bit_not();
+        gen_bitset!(@and_rest $dst, $bound; $($tail)*);
+    };
+    // A `maybe` factor matches everything.
+    (@first $dst:ident, $bound:ident; maybe &mut $st:ident $($tail:tt)*) => {
+        $dst.copy_bounded(&$crate::LayeredBitSet::default(), $bound).bit_not();
+        gen_bitset!(@and_rest $dst, $bound; $($tail)*);
+    };
+    (@first $dst:ident, $bound:ident; maybe &$st:ident $($tail:tt)*) => {
+        $dst.copy_bounded(&$crate::LayeredBitSet::default(), $bound).bit_not();
+        gen_bitset!(@and_rest $dst, $bound; $($tail)*);
+    };
+    (@first $dst:ident, $bound:ident; !($($inner:tt)*) $($tail:tt)*) => {
+        gen_bitset!(@or $dst, $bound; first; [] $($inner)*);
+        $dst.bit_not();
+        gen_bitset!(@and_rest $dst, $bound; $($tail)*);
+    };
+    (@first $dst:ident, $bound:ident; ($($inner:tt)*) $($tail:tt)*) => {
+        gen_bitset!(@or $dst, $bound; first; [] $($inner)*);
+        gen_bitset!(@and_rest $dst, $bound; $($tail)*);
+    };
+    // The following factors of a `&&` chain.
+    (@and_rest $dst:ident, $bound:ident;) => {};
+    (@and_rest $dst:ident, $bound:ident; && &mut $st:ident $($tail:tt)*) => {
+        $dst.bit_and($st.bitset());
+        gen_bitset!(@and_rest $dst, $bound; $($tail)*);
+    };
+    (@and_rest $dst:ident, $bound:ident; && &$st:ident $($tail:tt)*) => {
+        $dst.bit_and($st.bitset());
+        gen_bitset!(@and_rest $dst, $bound; $($tail)*);
+    };
+    (@and_rest $dst:ident, $bound:ident; && !&$st:ident $($tail:tt)*) => {
+        $dst.bit_andnot($st.bitset());
+        gen_bitset!(@and_rest $dst, $bound; $($tail)*);
+    };
+    (@and_rest $dst:ident, $bound:ident; && maybe &mut $st:ident $($tail:tt)*) => {
+        gen_bitset!(@and_rest $dst, $bound; $($tail)*);
+    };
+    (@and_rest $dst:ident, $bound:ident; && maybe &$st:ident $($tail:tt)*) => {
+        gen_bitset!(@and_rest $dst, $bound; $($tail)*);
+    };
+    (@and_rest $dst:ident, $bound:ident; && !($($inner:tt)*) $($tail:tt)*) => {
+        // Groups are skipped once nothing is left to filter.
+        if !$dst.is_empty() {
+            let mut group = $crate::LayeredBitSet::default();
+            gen_bitset!(@or group, $bound; first; [] $($inner)*);
+            $dst.bit_andnot(&group);
+        }
+        gen_bitset!(@and_rest $dst, $bound; $($tail)*);
+    };
+    (@and_rest $dst:ident, $bound:ident; && ($($inner:tt)*) $($tail:tt)*) => {
+        if !$dst.is_empty() {
+            let mut group = $crate::LayeredBitSet::default();
+            gen_bitset!(@or group, $bound; first; [] $($inner)*);
+            $dst.bit_and(&group);
+        }
+        gen_bitset!(@and_rest $dst, $bound; $($tail)*);
+    };
+    ($bitset:ident, $bound:ident; $($complex:tt)*) => {
+        let dst = std::rc::Rc::get_mut(&mut $bitset).unwrap();
+        gen_bitset!(@or dst, $bound; first; [] $($complex)*);
+    };
+}
+
+/// Classifies the storages of a join expression, in source order, then
+/// passes them to `$cb` as a list of `[req &st]`, `[opt &st]` or `[not st]`.
+///
+/// A storage is required (`req`) when every match of the expression has a
+/// component in it, which is the case when it is not under a `||` or a `!`.
+/// Storages under a `!` (`not`) yield no value, and `maybe` ones are always
+/// optional (`opt`).
+#[doc(hidden)]
+#[macro_export]
+macro_rules! join_terms {
+    // Looks for a `||` at the top level of a group of required terms.
+    (@scan $cb:ident ($($args:tt)*) [$($out:tt)*] [|| $($x:tt)*] [$($group:tt)*] $($stack:tt)*) => {
+        join_terms!(@go $cb ($($args)*) [$($out)*] {opt [$($group)*]} $($stack)*)
+    };
+    (@scan $cb:ident ($($args:tt)*) [$($out:tt)*] [$x:tt $($y:tt)*] [$($group:tt)*] $($stack:tt)*) => {
+        join_terms!(@scan $cb ($($args)*) [$($out)*] [$($y)*] [$($group)*] $($stack)*)
+    };
+    (@scan $cb:ident ($($args:tt)*) [$($out:tt)*] [] [$($group:tt)*] $($stack:tt)*) => {
+        join_terms!(@go $cb ($($args)*) [$($out)*] {req [$($group)*]} $($stack)*)
+    };
+    // Walks the groups, the stack holding what is left of the enclosing ones.
+    (@go $cb:ident ($($args:tt)*) [$($out:tt)*]) => {
+        $cb!($($args)* ; $($out)*)
+    };
+    (@go $cb:ident ($($args:tt)*) [$($out:tt)*] {$mode:ident []} $($stack:tt)*) => {
+        join_terms!(@go $cb ($($args)*) [$($out)*] $($stack)*)
     };
-    ($bitset:ident; &$st:ident $($tail:tt)*) => {
-        *std::rc::Rc::get_mut(&mut $bitset).unwrap() = $st.bitset().clone();
-        gen_bitset!($bitset; $($tail)*);
+    (@go $cb:ident ($($args:tt)*) [$($out:tt)*] {$mode:ident [&mut $st:ident $($tail:tt)*]} $($stack:tt)*) => {
+        join_terms!(@go $cb ($($args)*) [$($out)* [$mode &mut $st]] {$mode [$($tail)*]} $($stack)*)
     };
-    ($bitset:ident; !&$st:ident $($tail:tt)*) => {
-        let mut cloned = $st.bitset().clone();
-        cloned.bit_not();
-        *std::rc::Rc::get_mut(&mut $bitset).unwrap() = cloned;
-        gen_bitset!($bitset; $($tail)*);
+    (@go $cb:ident ($($args:tt)*) [$($out:tt)*] {$mode:ident [&$st:ident $($tail:tt)*]} $($stack:tt)*) => {
+        join_terms!(@go $cb ($($args)*) [$($out)* [$mode &$st]] {$mode [$($tail)*]} $($stack)*)
     };
-    ($bitset:ident; && &mut $st:ident $($tail:tt)*) => {
-        std::rc::Rc::get_mut(&mut $bitset).unwrap().bit_and($st.bitset());
-        gen_bitset!($bitset; $($tail)*);
+    (@go $cb:ident ($($args:tt)*) [$($out:tt)*] {$mode:ident [!&$st:ident $($tail:tt)*]} $($stack:tt)*) => {
+        join_terms!(@go $cb ($($args)*) [$($out)* [not $st]] {$mode [$($tail)*]} $($stack)*)
     };
-    ($bitset:ident; && &$st:ident $($tail:tt)*) => {
-        std::rc::Rc::get_mut(&mut $bitset).unwrap().bit_and($st.bitset());
-        gen_bitset!($bitset; $($tail)*);
+    (@go $cb:ident ($($args:tt)*) [$($out:tt)*] {not [maybe &mut $st:ident $($tail:tt)*]} $($stack:tt)*) => {
+        join_terms!(@go $cb ($($args)*) [$($out)* [not $st]] {not [$($tail)*]} $($stack)*)
     };
-    ($bitset:ident; && !&$st:ident $($tail:tt)*) => {
-        std::rc::Rc::get_mut(&mut $bitset).unwrap().bit_andnot($st.bitset());
-        gen_bitset!($bitset; $($tail)*);
+    (@go $cb:ident ($($args:tt)*) [$($out:tt)*] {not [maybe &$st:ident $($tail:tt)*]} $($stack:tt)*) => {
+        join_terms!(@go $cb ($($args)*) [$($out)* [not $st]] {not [$($tail)*]} $($stack)*)
     };
-    ($bitset:ident; || &mut $st:ident $($tail:tt)*) => {
-        std::rc::Rc::get_mut(&mut $bitset).unwrap().bit_or($st.bitset());
-        gen_bitset!($bitset; $($tail)*);
+    (@go $cb:ident ($($args:tt)*) [$($out:tt)*] {$mode:ident [maybe &mut $st:ident $($tail:tt)*]} $($stack:tt)*) => {
+        join_terms!(@go $cb ($($args)*) [$($out)* [opt &mut $st]] {$mode [$($tail)*]} $($stack)*)
     };
-    ($bitset:ident; || &$st:ident $($tail:tt)*) => {
-        std::rc::Rc::get_mut(&mut $bitset).unwrap().bit_or($st.bitset());
-        gen_bitset!($bitset; $($tail)*);
+    (@go $cb:ident ($($args:tt)*) [$($out:tt)*] {$mode:ident [maybe &$st:ident $($tail:tt)*]} $($stack:tt)*) => {
+        join_terms!(@go $cb ($($args)*) [$($out)* [opt &$st]] {$mode [$($tail)*]} $($stack)*)
     };
-    ($bitset:ident; || !&$st:ident $($tail:tt)*) => {
-        std::rc::Rc::get_mut(&mut $bitset).unwrap().bit_or($st.bitset().clone().bit_not());
-        gen_bitset!($bitset; $($tail)*);
+    (@go $cb:ident ($($args:tt)*) [$($out:tt)*] {$mode:ident [&& $($tail:tt)*]} $($stack:tt)*) => {
+        join_terms!(@go $cb ($($args)*) [$($out)*] {$mode [$($tail)*]} $($stack)*)
     };
-    // scopes
-    /*($bitset:ident; && ($($inner:tt)*) $($tail:tt)*) => {
-        $bitset.bit_and({gen_bitset!($bitset; $($inner:tt)*); $bitset});
-        gen_bitset!($bitset; $($tail)*);
+    (@go $cb:ident ($($args:tt)*) [$($out:tt)*] {$mode:ident [|| $($tail:tt)*]} $($stack:tt)*) => {
+        join_terms!(@go $cb ($($args)*) [$($out)*] {$mode [$($tail)*]} $($stack)*)
     };
-    ($bitset:ident; && !($($inner:tt)*) $($tail:tt)*) => {
-        $bitset.bit_andnot({gen_bitset!($bitset; $($inner:tt)*); $bitset});
-        gen_bitset!($bitset; $($tail)*);
+    (@go $cb:ident ($($args:tt)*) [$($out:tt)*] {$mode:ident [!($($group:tt)*) $($tail:tt)*]} $($stack:tt)*) => {
+        join_terms!(@go $cb ($($args)*) [$($out)*] {not [$($group)*]} {$mode [$($tail)*]} $($stack)*)
     };
-    ($bitset:ident; || ($($inner:tt)*) $($tail:tt)*) => {
-        $bitset.bit_or({gen_bitset!($bitset; $($inner:tt)*); $bitset});
-        gen_bitset!($bitset; $($tail)*);
+    (@go $cb:ident ($($args:tt)*) [$($out:tt)*] {req [($($group:tt)*) $($tail:tt)*]} $($stack:tt)*) => {
+        join_terms!(@scan $cb ($($args)*) [$($out)*] [$($group)*] [$($group)*] {req [$($tail)*]} $($stack)*)
+    };
+    (@go $cb:ident ($($args:tt)*) [$($out:tt)*] {$mode:ident [($($group:tt)*) $($tail:tt)*]} $($stack:tt)*) => {
+        join_terms!(@go $cb ($($args)*) [$($out)*] {$mode [$($group)*]} {$mode [$($tail)*]} $($stack)*)
+    };
+    ($cb:ident!($($args:tt)*); $($complex:tt)*) => {
+        join_terms!(@scan $cb ($($args)*) [] [$($complex)*] [$($complex)*])
     };
-    ($bitset:ident; || !($($inner:tt)*) $($tail:tt)*) => {
-        $bitset.bit_or({gen_bitset!($bitset; $($inner:tt)*); $bitset}.bit_not());
-        gen_bitset!($bitset; $($tail)*);
-    };*/
 }
 
 #[doc(hidden)]
 #[macro_export]
 macro_rules! iter_bitset {
+    // Only negated terms: there is nothing to yield but the matches.
+    ($bitset:ident ; ;) => {std::iter::repeat(()).take($bitset.bit_count())};
     ($bitset:ident ; $(,)?$($idents:block),* ;) => {izip!($($idents),*)};
-    ($bitset:ident ; $(,)?$($idents:block),* ; &mut $st:ident $($tail:tt)*) => {
-        iter_bitset!($bitset; $($idents),* , {$st.iter_mut_with_bitset($bitset.clone())} ; $($tail)*)
+    ($bitset:ident ; $(,)?$($idents:block),* ; [req &mut $st:ident] $($tail:tt)*) => {
+        iter_bitset!($bitset; $($idents),* , {$st.iter_mut_with_bitset($bitset.clone()).map(Option::unwrap)} ; $($tail)*)
     };
-    ($bitset:ident ; $(,)?$($idents:block),* ; &$st:ident $($tail:tt)*) => {
-        iter_bitset!($bitset; $($idents),* , {$st.iter_with_bitset($bitset.clone())} ; $($tail)*)
+    ($bitset:ident ; $(,)?$($idents:block),* ; [req &$st:ident] $($tail:tt)*) => {
+        iter_bitset!($bitset; $($idents),* , {$st.iter_with_bitset($bitset.clone()).map(Option::unwrap)} ; $($tail)*)
     };
-    ($bitset:ident ; $(,)?$($idents:block),* ; !&$st:ident $($tail:tt)*) => {
-        iter_bitset!($bitset; $($idents),* , {$st.iter_with_bitset($bitset.clone())} ; $($tail)*)
+    ($bitset:ident ; $(,)?$($idents:block),* ; [opt &mut $st:ident] $($tail:tt)*) => {
+        iter_bitset!($bitset; $($idents),* , {$st.iter_mut_with_bitset($bitset.clone())} ; $($tail)*)
     };
-    ($bitset:ident ; $(,)?$($idents:block),* ; && $($tail:tt)*) => {
-        iter_bitset!($bitset; $($idents),* ; $($tail)*)
+    ($bitset:ident ; $(,)?$($idents:block),* ; [opt &$st:ident] $($tail:tt)*) => {
+        iter_bitset!($bitset; $($idents),* , {$st.iter_with_bitset($bitset.clone())} ; $($tail)*)
     };
-    ($bitset:ident ; $(,)?$($idents:block),* ; || $($tail:tt)*) => {
+    ($bitset:ident ; $(,)?$($idents:block),* ; [not $($term:tt)*] $($tail:tt)*) => {
         iter_bitset!($bitset; $($idents),* ; $($tail)*)
     };
-    /*($bitset:ident; $(,)?$($idents:block),*; ($($tail:tt)*)) => {
-        iter_bitset!($bitset; $($idents)*; $($tail)*)
+}
+
+/// Recomputes the bitset cached in `$query` if one of the storages changed.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! query_bitset {
+    ($query:ident; $($complex:tt)*) => {
+        let versions = bitset_versions!(; $($complex)*);
+        if $query.is_outdated(stringify!($($complex)*), &versions) {
+            let bound = bitset_bound!(0usize; $($complex)*);
+            let mut bitset = $query.take_bitset();
+            gen_bitset!(bitset, bound; $($complex)*);
+            $query.set_bitset(bitset);
+        }
     };
-    ($bitset:ident; $(,)?$($idents:block),*; !($($tail:tt)*)) => {
-        iter_bitset!($bitset; $($idents)*; $($tail)*)
-    };*/
 }
 
 /// The join macro makes it very easy to iterate over multiple
@@ -101,7 +341,7 @@ macro_rules! iter_bitset {
 /// components should or should not be matched.
 /// Here is an example:
 /// ```rust,ignore
-/// let iter = join!(&storage1 && &mut storage2 || &mut storage3 && !&storage4);
+/// let iter = join!(&storage1 && (&mut storage2 || &mut storage3) && !&storage4);
 /// ```
 ///
 /// Here, we first provide a bitset. This is due to a limitation with rust
@@ -115,11 +355,40 @@ macro_rules! iter_bitset {
 ///
 /// We also specify that storage2 and storage3 should be accessed mutably.
 ///
+/// As in rust, `&&` binds tighter than `||`, and parentheses can be used to
+/// group terms, including negated groups like `!(&storage3 || &storage4)`.
+///
+/// A `maybe` term fetches the component when the entity has one, without
+/// filtering the matched entities:
+/// ```rust,ignore
+/// join!(&positions && maybe &velocities).for_each(|(position, velocity)| {});
+/// ```
+/// Here, `velocity` is an `Option<&Velocity>`.
+///
 /// Finally, we can iterate:
 /// ```rust,ignore
-/// iter.for_each(|(component1, mut component2, mut component3, _)| {});
+/// iter.for_each(|(component1, component2, component3)| {});
+/// ```
+/// This iterator will be of type `(&T1, Option<&mut T2>, Option<&mut T3>)`.
+/// Components that every match has, like the ones of storage1, are returned
+/// directly. Those reachable through a `||` are returned as an `Option`.
+/// Storages under a `!` are not returned since the matched entities do not
+/// have them.
+///
+/// A storage borrowed mutably can only appear once in a join, which the
+/// borrow checker enforces:
+/// ```compile_fail
+/// use entity_component::*;
+/// let mut storage = Components::<u32>::default();
+/// join!(&mut storage && &storage).for_each(|_| {});
+/// ```
+///
+/// To avoid computing the bitset again on each call, a `Query` can be
+/// provided before the expression. The bitset is then only recomputed when
+/// one of the storages changed:
+/// ```rust,ignore
+/// join!(query => &mut storage1 && &storage2).for_each(|(component1, component2)| {});
 /// ```
-/// This iterator will be of type `(Option<&T1>, Option<&mut T2>, ...)`.
 #[macro_export]
 macro_rules! join {
     (&$st:ident) => {
@@ -128,17 +397,472 @@ macro_rules! join {
     (&mut $st:ident) => {
         $st.iter_mut()
     };
+    ($query:ident => $($complex:tt)*) => {
+        {
+            query_bitset!($query; $($complex)*);
+            let bitset = $query.bitset().clone();
+            let iter = join_terms!(iter_bitset!(bitset ;); $($complex)*);
+            iter
+        }
+    };
     ($($complex:tt)*) => {
         {
-            // TODO find a way to avoid having this first vec allocation.
-            let mut bitset = std::rc::Rc::new(vec![]);
-            gen_bitset!(bitset; $($complex)*);
-            let iter = iter_bitset!(bitset ; ; $($complex)*);
+            // Use a `Query` to avoid allocating a new bitset on each call.
+            // Only the blocks up to the highest index populated by one of
+            // the storages are touched.
+            let bound = bitset_bound!(0usize; $($complex)*);
+            let mut bitset = std::rc::Rc::new($crate::LayeredBitSet::default());
+            gen_bitset!(bitset, bound; $($complex)*);
+            let iter = join_terms!(iter_bitset!(bitset ;); $($complex)*);
             iter
         }
     };
 }
 
+/// Returns how many entities a `join!` expression matches.
+///
+/// The count is computed from the bitset of the join, without touching the
+/// components. A `Query` can be provided like with `join!`:
+/// ```rust,ignore
+/// let idle = join_count!(&units && !&orders);
+/// let idle = join_count!(query => &units && !&orders);
+/// ```
+#[macro_export]
+macro_rules! join_count {
+    ($query:ident => $($complex:tt)*) => {
+        {
+            query_bitset!($query; $($complex)*);
+            $query.count()
+        }
+    };
+    ($($complex:tt)*) => {
+        {
+            let bound = bitset_bound!(0usize; $($complex)*);
+            let mut bitset = std::rc::Rc::new($crate::LayeredBitSet::default());
+            gen_bitset!(bitset, bound; $($complex)*);
+            bitset.bit_count()
+        }
+    };
+}
+
+/// Returns true if a `join!` expression matches at least one entity.
+///
+/// Like `join_count!`, only the bitset of the join is computed.
+/// ```rust,ignore
+/// if !join_any!(&players && !&dead) {
+///     game_over();
+/// }
+/// ```
+#[macro_export]
+macro_rules! join_any {
+    ($query:ident => $($complex:tt)*) => {
+        {
+            query_bitset!($query; $($complex)*);
+            !$query.is_empty()
+        }
+    };
+    ($($complex:tt)*) => {
+        {
+            let bound = bitset_bound!(0usize; $($complex)*);
+            let mut bitset = std::rc::Rc::new($crate::LayeredBitSet::default());
+            gen_bitset!(bitset, bound; $($complex)*);
+            !bitset.is_empty()
+        }
+    };
+}
+
+/// The entity indices matched by a `Join`, up to a bound.
+pub enum JoinMask<'a> {
+    /// Matches every index, like `Maybe`.
+    All,
+    /// The bitset of a storage.
+    Borrowed(&'a LayeredBitSet),
+    /// A bitset computed for this join, bounded to the join bound.
+    Owned(LayeredBitSet),
+}
+
+impl<'a> JoinMask<'a> {
+    /// Returns the matched indices as a bitset whose high-water mark is `bound`.
+    pub fn into_owned(self, bound: usize) -> LayeredBitSet {
+        match self {
+            JoinMask::All => {
+                let mut all = LayeredBitSet::default().bounded(bound);
+                all.bit_not();
+                all
+            }
+            JoinMask::Borrowed(bitset) => bitset.bounded(bound),
+            JoinMask::Owned(bitset) => bitset,
+        }
+    }
+    /// Matches the indices matched by both masks.
+    pub fn and(self, rhs: JoinMask<'a>, bound: usize) -> JoinMask<'a> {
+        match (self, rhs) {
+            (JoinMask::All, mask) | (mask, JoinMask::All) => mask,
+            (JoinMask::Owned(mut lhs), JoinMask::Borrowed(rhs))
+            | (JoinMask::Borrowed(rhs), JoinMask::Owned(mut lhs)) => {
+                lhs.bit_and(rhs);
+                JoinMask::Owned(lhs)
+            }
+            (JoinMask::Owned(mut lhs), JoinMask::Owned(rhs)) => {
+                lhs.bit_and(&rhs);
+                JoinMask::Owned(lhs)
+            }
+            (JoinMask::Borrowed(lhs), JoinMask::Borrowed(rhs)) => {
+                let mut lhs = lhs.bounded(bound);
+                lhs.bit_and(rhs);
+                JoinMask::Owned(lhs)
+            }
+        }
+    }
+    /// Matches the indices matched by either mask.
+    pub fn or(self, rhs: JoinMask<'a>, bound: usize) -> JoinMask<'a> {
+        match (self, rhs) {
+            (JoinMask::All, _) | (_, JoinMask::All) => JoinMask::All,
+            (JoinMask::Owned(mut lhs), JoinMask::Borrowed(rhs))
+            | (JoinMask::Borrowed(rhs), JoinMask::Owned(mut lhs)) => {
+                lhs.bit_or(rhs);
+                JoinMask::Owned(lhs)
+            }
+            (JoinMask::Owned(mut lhs), JoinMask::Owned(rhs)) => {
+                lhs.bit_or(&rhs);
+                JoinMask::Owned(lhs)
+            }
+            (JoinMask::Borrowed(lhs), JoinMask::Borrowed(rhs)) => {
+                let mut lhs = lhs.bounded(bound);
+                lhs.bit_or(rhs);
+                JoinMask::Owned(lhs)
+            }
+        }
+    }
+    /// Matches the indices below `bound` that this mask does not match.
+    pub fn not(self, bound: usize) -> JoinMask<'a> {
+        match self {
+            JoinMask::All => JoinMask::Owned(LayeredBitSet::default().bounded(bound)),
+            mask => {
+                let mut bitset = mask.into_owned(bound);
+                bitset.bit_not();
+                JoinMask::Owned(bitset)
+            }
+        }
+    }
+}
+
+/// A storage, or a combination of storages, that can be iterated over for
+/// the entities matched by all of them.
+///
+/// This is the composable alternative to `join!`: it works on any
+/// expression and in generic code.
+/// ```rust,ignore
+/// (&entities, &mut self.positions, &world.velocities, Maybe(&names), Not(&frozen))
+///     .join()
+///     .for_each(|(entity, position, velocity, name, ())| {});
+/// ```
+/// `Or(a, b)` matches the entities in either and yields a pair of `Option`.
+///
+/// As with `join!`, a storage borrowed mutably can only appear once:
+/// ```compile_fail
+/// use entity_component::*;
+/// let mut storage = Components::<u32>::default();
+/// (&mut storage, Maybe(&mut storage)).join().for_each(|_| {});
+/// ```
+pub trait Join {
+    /// The value yielded for each matched entity.
+    type Item;
+    /// Gives access to the values by entity index once the join started.
+    type Values;
+    /// The values of a block of 256 entities, used by `join_chunks`.
+    type Chunk;
+    /// One past the highest entity index this term can match.
+    fn high_water(&self) -> usize;
+    /// Returns the entity indices below `bound` that this term matches.
+    fn mask(&self, bound: usize) -> JoinMask<'_>;
+    /// Starts the join, giving up the access to the storages.
+    fn open(self) -> Self::Values;
+    /// Returns true if there is a value at index `id`.
+    fn contains(values: &Self::Values, id: usize) -> bool;
+    /// Gets the value at index `id`.
+    ///
+    /// # Safety
+    /// `id` must be matched by the mask of this term, and must be requested
+    /// at most once, as mutable storages hand out `&mut` references.
+    unsafe fn fetch(values: &mut Self::Values, id: usize) -> Self::Item;
+    /// Gets the values of the entities of block `block`, which are the
+    /// indices from `block * 256` to `block * 256 + 255`.
+    ///
+    /// # Safety
+    /// Each block must be requested at most once.
+    unsafe fn fetch_chunk(values: &mut Self::Values, block: usize) -> Self::Chunk;
+    /// Iterates over the values of the entities matched by this term.
+    fn join(self) -> JoinIter<Self>
+    where
+        Self: Sized,
+    {
+        let bound = self.high_water();
+        let bitset = self.mask(bound).into_owned(bound);
+        JoinIter {
+            bitset,
+            current_id: 0,
+            values: self.open(),
+        }
+    }
+    /// Iterates over the blocks of 256 entities having at least one match,
+    /// for batch processing.
+    ///
+    /// Each item is the index of the first entity of the block, the mask of
+    /// the matched entities in the block and the values of the block.
+    /// For a `Components`, those values are the slice of the storage covering
+    /// the block. It is shorter than 256 when the storage ends in the block.
+    /// ```rust,ignore
+    /// (&mut positions, &velocities).join_chunks().for_each(|(base, mask, (p, v))| {
+    ///     for i in 0..p.len().min(v.len()) {
+    ///         if mask[i / 32] & (1 << (i % 32)) != 0 {
+    ///             p[i].as_mut().unwrap().0 += v[i].as_ref().unwrap().0;
+    ///         }
+    ///     }
+    /// });
+    /// ```
+    fn join_chunks(self) -> JoinChunks<Self>
+    where
+        Self: Sized,
+    {
+        let bound = self.high_water();
+        let bitset = self.mask(bound).into_owned(bound);
+        JoinChunks {
+            bitset,
+            current_id: 0,
+            values: self.open(),
+        }
+    }
+    /// Gets the values of a single `Entity`, if it is alive in `entities`
+    /// and matched by this term.
+    ///
+    /// Useful for random access inside of another loop. Values are mutable
+    /// for `&mut` storages, so `(&mut a, &b).join_get(&entities, entity)`
+    /// acts as a `get_mut`.
+    fn join_get(self, entities: &Entities, entity: Entity) -> Option<Self::Item>
+    where
+        Self: Sized,
+    {
+        let id = entity.index() as usize;
+        let mut values = self.open();
+        if entities.is_alive(entity) && Self::contains(&values, id) {
+            // Unsafe: the index is matched and only one value is fetched.
+            Some(unsafe { Self::fetch(&mut values, id) })
+        } else {
+            None
+        }
+    }
+    /// Iterates over the values of the given entities, in the order of
+    /// `subset`, without computing the bitset of the whole join.
+    ///
+    /// Entities that are no longer alive in `entities` or that are not matched
+    /// by this term are skipped. An entity appearing multiple times in
+    /// `subset` is only yielded the first time.
+    /// An `EntitySet` can be used through `EntitySet::entities`.
+    fn join_subset<I: IntoIterator<Item = Entity>>(
+        self,
+        entities: &Entities,
+        subset: I,
+    ) -> SubsetJoinIter<'_, Self, I::IntoIter>
+    where
+        Self: Sized,
+    {
+        SubsetJoinIter {
+            entities,
+            subset: subset.into_iter(),
+            seen: LayeredBitSet::default(),
+            values: self.open(),
+        }
+    }
+}
+
+/// Iterator over the values of a `Join`.
+pub struct JoinIter<J: Join> {
+    bitset: LayeredBitSet,
+    current_id: usize,
+    values: J::Values,
+}
+
+impl<J: Join> Iterator for JoinIter<J> {
+    type Item = J::Item;
+    fn next(&mut self) -> Option<Self::Item> {
+        let id = self.bitset.next_set(self.current_id)?;
+        self.current_id = id + 1;
+        // Unsafe: the index is in the mask and is never visited again.
+        Some(unsafe { J::fetch(&mut self.values, id) })
+    }
+}
+
+/// Iterator over the blocks of 256 entities of a `Join`.
+pub struct JoinChunks<J: Join> {
+    bitset: LayeredBitSet,
+    current_id: usize,
+    values: J::Values,
+}
+
+impl<J: Join> Iterator for JoinChunks<J> {
+    type Item = (usize, [u32; 8], J::Chunk);
+    fn next(&mut self) -> Option<Self::Item> {
+        let block = self.bitset.next_set(self.current_id)? / 256;
+        self.current_id = (block + 1) * 256;
+        // Unsafe: the blocks are visited in increasing order, so only once.
+        let chunk = unsafe { J::fetch_chunk(&mut self.values, block) };
+        Some((block * 256, self.bitset.blocks()[block], chunk))
+    }
+}
+
+/// Iterator over the values of a `Join` for a list of entities.
+pub struct SubsetJoinIter<'a, J: Join, I> {
+    entities: &'a Entities,
+    subset: I,
+    /// The indices already yielded. Only grows up to the highest one.
+    seen: LayeredBitSet,
+    values: J::Values,
+}
+
+impl<'a, J: Join, I: Iterator<Item = Entity>> Iterator for SubsetJoinIter<'a, J, I> {
+    type Item = J::Item;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entity = self.subset.next()?;
+            let id = entity.index() as usize;
+            if self.entities.is_alive(entity) && !self.seen.bit_test(id) && J::contains(&self.values, id) {
+                self.seen.bit_set(id);
+                // Unsafe: the index is matched and was not visited before.
+                return Some(unsafe { J::fetch(&mut self.values, id) });
+            }
+        }
+    }
+}
+
+/// Joins over a storage without filtering on it, yielding `None` for the
+/// entities that do not have a value in it.
+pub struct Maybe<J>(pub J);
+
+impl<J: Join> Join for Maybe<J> {
+    type Item = Option<J::Item>;
+    type Values = J::Values;
+    type Chunk = J::Chunk;
+    fn high_water(&self) -> usize {
+        self.0.high_water()
+    }
+    fn mask(&self, _bound: usize) -> JoinMask<'_> {
+        JoinMask::All
+    }
+    fn open(self) -> Self::Values {
+        self.0.open()
+    }
+    fn contains(_values: &Self::Values, _id: usize) -> bool {
+        true
+    }
+    unsafe fn fetch(values: &mut Self::Values, id: usize) -> Self::Item {
+        if J::contains(values, id) {
+            Some(J::fetch(values, id))
+        } else {
+            None
+        }
+    }
+    unsafe fn fetch_chunk(values: &mut Self::Values, block: usize) -> Self::Chunk {
+        J::fetch_chunk(values, block)
+    }
+}
+
+/// Matches the entities that do not have a value in a storage.
+pub struct Not<J>(pub J);
+
+impl<J: Join> Join for Not<J> {
+    type Item = ();
+    type Values = J::Values;
+    type Chunk = ();
+    fn high_water(&self) -> usize {
+        self.0.high_water()
+    }
+    fn mask(&self, bound: usize) -> JoinMask<'_> {
+        self.0.mask(bound).not(bound)
+    }
+    fn open(self) -> Self::Values {
+        self.0.open()
+    }
+    fn contains(values: &Self::Values, id: usize) -> bool {
+        !J::contains(values, id)
+    }
+    unsafe fn fetch(_values: &mut Self::Values, _id: usize) -> Self::Item {}
+    unsafe fn fetch_chunk(_values: &mut Self::Values, _block: usize) -> Self::Chunk {}
+}
+
+/// Matches the entities that have a value in either storage.
+pub struct Or<A, B>(pub A, pub B);
+
+impl<A: Join, B: Join> Join for Or<A, B> {
+    type Item = (Option<A::Item>, Option<B::Item>);
+    type Values = (A::Values, B::Values);
+    type Chunk = (A::Chunk, B::Chunk);
+    fn high_water(&self) -> usize {
+        self.0.high_water().max(self.1.high_water())
+    }
+    fn mask(&self, bound: usize) -> JoinMask<'_> {
+        self.0.mask(bound).or(self.1.mask(bound), bound)
+    }
+    fn open(self) -> Self::Values {
+        (self.0.open(), self.1.open())
+    }
+    fn contains(values: &Self::Values, id: usize) -> bool {
+        A::contains(&values.0, id) || B::contains(&values.1, id)
+    }
+    unsafe fn fetch(values: &mut Self::Values, id: usize) -> Self::Item {
+        let a = if A::contains(&values.0, id) {
+            Some(A::fetch(&mut values.0, id))
+        } else {
+            None
+        };
+        let b = if B::contains(&values.1, id) {
+            Some(B::fetch(&mut values.1, id))
+        } else {
+            None
+        };
+        (a, b)
+    }
+    unsafe fn fetch_chunk(values: &mut Self::Values, block: usize) -> Self::Chunk {
+        (A::fetch_chunk(&mut values.0, block), B::fetch_chunk(&mut values.1, block))
+    }
+}
+
+macro_rules! impl_join_tuple {
+    ($($j:ident $i:tt),*) => {
+        impl<$($j: Join),*> Join for ($($j,)*) {
+            type Item = ($($j::Item,)*);
+            type Values = ($($j::Values,)*);
+            type Chunk = ($($j::Chunk,)*);
+            fn high_water(&self) -> usize {
+                0 $(.max(self.$i.high_water()))*
+            }
+            fn mask(&self, bound: usize) -> JoinMask<'_> {
+                JoinMask::All $(.and(self.$i.mask(bound), bound))*
+            }
+            fn open(self) -> Self::Values {
+                ($(self.$i.open(),)*)
+            }
+            fn contains(values: &Self::Values, id: usize) -> bool {
+                true $(&& $j::contains(&values.$i, id))*
+            }
+            unsafe fn fetch(values: &mut Self::Values, id: usize) -> Self::Item {
+                ($($j::fetch(&mut values.$i, id),)*)
+            }
+            unsafe fn fetch_chunk(values: &mut Self::Values, block: usize) -> Self::Chunk {
+                ($($j::fetch_chunk(&mut values.$i, block),)*)
+            }
+        }
+    };
+}
+
+impl_join_tuple!(A 0, B 1);
+impl_join_tuple!(A 0, B 1, C 2);
+impl_join_tuple!(A 0, B 1, C 2, D 3);
+impl_join_tuple!(A 0, B 1, C 2, D 3, E 4);
+impl_join_tuple!(A 0, B 1, C 2, D 3, E 4, F 5);
+impl_join_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_join_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
 #[cfg(test)]
 mod tests {
     use crate::*;
@@ -160,12 +884,365 @@ mod tests {
         let storage2 = Components::<B>::default();
         let storage3 = Components::<C>::default();
         let mut count = 0;
-        join!(&mut storage1 && &storage2 || !&storage3).for_each(|(_a, _b, _c)| {
+        join!(&mut storage1 && &storage2 || !&storage3).for_each(|(_a, _b)| {
             count += 1;
         });
         assert_eq!(count, 0);
     }
 
+    #[test]
+    fn sparse_join() {
+        struct A(usize);
+        struct B;
+        let mut entities = Entities::default();
+        let mut comp1 = Components::<A>::default();
+        let mut comp2 = Components::<B>::default();
+        for i in 0..60000 {
+            let e = entities.create();
+            if i % 6000 == 0 {
+                comp1.insert(e, A(i));
+            }
+            if i % 2 == 0 {
+                comp2.insert(e, B);
+            }
+        }
+        let matched = join!(&entities && &comp1 && &comp2)
+            .map(|(e, a, _)| (e.index() as usize, a.0))
+            .collect::<Vec<_>>();
+        assert_eq!(matched, (0..10).map(|i| (i * 6000, i * 6000)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn not_past_high_water() {
+        struct A;
+        struct B;
+        let mut entities = Entities::default();
+        let mut comp1 = Components::<A>::default();
+        let mut comp2 = Components::<B>::default();
+        for i in 0..1000 {
+            let e = entities.create();
+            if i < 10 {
+                comp1.insert(e, A);
+            }
+            comp2.insert(e, B);
+        }
+        assert_eq!(comp1.bitset().high_water(), 10);
+        assert_eq!(join!(!&comp1 && &comp2).count(), 990);
+        assert_eq!(join!(&entities && !&comp1).count(), 990);
+        assert_eq!(join!(&comp2 && !&comp1).count(), 990);
+    }
+
+    #[test]
+    // The expected truth tables mirror the join expressions.
+    #[allow(clippy::nonminimal_bool)]
+    fn precedence_and_grouping() {
+        struct A;
+        struct B;
+        struct C;
+        let mut entities = Entities::default();
+        let mut a = Components::<A>::default();
+        let mut b = Components::<B>::default();
+        let mut c = Components::<C>::default();
+        // Entity `i` has A if bit 0 of `i` is set, B for bit 1 and C for bit 2.
+        for i in 0..8 {
+            let e = entities.create();
+            if i & 1 != 0 {
+                a.insert(e, A);
+            }
+            if i & 2 != 0 {
+                b.insert(e, B);
+            }
+            if i & 4 != 0 {
+                c.insert(e, C);
+            }
+        }
+        macro_rules! check {
+            (($($expr:tt)*), $truth:expr) => {
+                let mut query = Query::new();
+                let count = join!(query => $($expr)*).count();
+                let matched = EntitySet::from(&**query.bitset()).iter().collect::<Vec<_>>();
+                assert_eq!(count, matched.len());
+                let truth: fn(bool, bool, bool) -> bool = $truth;
+                let expected = (0..8u32)
+                    .filter(|i| truth(i & 1 != 0, i & 2 != 0, i & 4 != 0))
+                    .collect::<Vec<_>>();
+                assert_eq!(matched, expected, stringify!($($expr)*));
+            };
+        }
+        check!((&a && &b || &c), |a, b, c| a && b || c);
+        check!((&a || &b && &c), |a, b, c| a || b && c);
+        check!((&a && !&b || &c && !&a), |a, b, c| a && !b || c && !a);
+        check!((&a && (&b || &c)), |a, b, c| a && (b || c));
+        check!((!(&a || &b) && &c), |a, b, c| !(a || b) && c);
+        check!((&a && !(&b && &c)), |a, b, c| a && !(b && c));
+        check!(((&a || &b) && (!&c || &a)), |a, b, c| (a || b) && (!c || a));
+        check!((!&a || !(&b || !&c)), |a, b, c| !a || !(b || !c));
+    }
+
+    #[test]
+    fn required_terms_unwrapped() {
+        struct A(u32);
+        struct B;
+        struct C;
+        struct D;
+        let mut entities = Entities::default();
+        let mut a = Components::<A>::default();
+        let mut b = Components::<B>::default();
+        let mut c = Components::<C>::default();
+        let mut d = Components::<D>::default();
+        for i in 0..4 {
+            let e = entities.create();
+            a.insert(e, A(0));
+            if i == 0 || i == 1 {
+                b.insert(e, B);
+            }
+            if i == 1 || i == 2 {
+                c.insert(e, C);
+            }
+            if i == 1 {
+                d.insert(e, D);
+            }
+        }
+        join!(&entities && &mut a && (&b || &c) && !&d).for_each(
+            |(e, a, b, c): (Entity, &mut A, Option<&B>, Option<&C>)| {
+                a.0 = e.index() + 1;
+                assert_eq!(b.is_some(), e.index() == 0);
+                assert_eq!(c.is_some(), e.index() == 2);
+            },
+        );
+        let matched = join!(&a).map(|a| a.0).collect::<Vec<_>>();
+        assert_eq!(matched, vec![1, 0, 3, 0]);
+    }
+
+    #[test]
+    fn join_trait() {
+        struct A(u32);
+        struct B;
+        struct C;
+        let mut entities = Entities::default();
+        let mut a = Components::<A>::default();
+        let mut b = Components::<B>::default();
+        let mut c = Components::<C>::default();
+        for i in 0..6 {
+            let e = entities.create();
+            a.insert(e, A(0));
+            if i % 2 == 0 {
+                b.insert(e, B);
+            }
+            if i % 3 == 0 {
+                c.insert(e, C);
+            }
+        }
+        struct World {
+            a: Components<A>,
+        }
+        let mut world = World { a };
+        (&entities, &mut world.a, Maybe(&b), Not(&c))
+            .join()
+            .for_each(|(e, a, b, ())| a.0 = e.index() * 10 + b.is_some() as u32);
+        let values = (&world.a).join().map(|a| a.0).collect::<Vec<_>>();
+        assert_eq!(values, vec![0, 10, 21, 0, 41, 50]);
+        assert_eq!(Or(&b, &c).join().count(), 4);
+        assert_eq!((&entities, Not(Or(&b, &c))).join().count(), 2);
+        assert_eq!((&b, Maybe(&c)).join().filter(|(_, c)| c.is_some()).count(), 1);
+    }
+
+    #[test]
+    fn maybe_does_not_filter() {
+        struct Position(u32);
+        struct Velocity(u32);
+        struct Frozen;
+        let mut entities = Entities::default();
+        let mut positions = Components::<Position>::default();
+        let mut velocities = Components::<Velocity>::default();
+        let mut frozen = Components::<Frozen>::default();
+        for i in 0..6 {
+            let e = entities.create();
+            positions.insert(e, Position(0));
+            if i % 2 == 0 {
+                velocities.insert(e, Velocity(i));
+            }
+            if i == 4 {
+                frozen.insert(e, Frozen);
+            }
+        }
+        join!(&mut positions && maybe &velocities && !&frozen).for_each(|(p, v)| {
+            p.0 = v.map(|v| v.0 + 1).unwrap_or(100);
+        });
+        let values = join!(&positions).map(|p| p.0).collect::<Vec<_>>();
+        assert_eq!(values, vec![1, 100, 3, 100, 0, 100]);
+        // Only the indices up to the highest component of the storages match.
+        assert_eq!(join!(maybe &velocities && !&frozen).count(), 4);
+        let mut query = Query::new();
+        assert_eq!(join!(query => &entities && maybe &mut velocities).count(), 6);
+    }
+
+    #[test]
+    fn join_subset() {
+        struct A(u32);
+        struct B;
+        let mut entities = Entities::default();
+        let mut a = Components::<A>::default();
+        let mut b = Components::<B>::default();
+        let list = (0..6)
+            .map(|i| {
+                let e = entities.create();
+                a.insert(e, A(i));
+                if i != 3 {
+                    b.insert(e, B);
+                }
+                e
+            })
+            .collect::<Vec<_>>();
+        entities.kill(list[1]);
+        let e6 = entities.create();
+        a.insert(e6, A(6));
+        b.insert(e6, B);
+
+        let subset = vec![list[5], list[1], list[3], list[0], list[5], e6];
+        let values = (&mut a, &b)
+            .join_subset(&entities, subset)
+            .map(|(a, _)| {
+                a.0 += 10;
+                a.0
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![15, 10, 16]);
+
+        let mut set = EntitySet::new();
+        set.insert(list[4]);
+        set.insert(list[2]);
+        let values = (&entities, &a)
+            .join_subset(&entities, set.entities(&entities))
+            .map(|(e, a)| (e, a.0))
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![(list[2], 2), (list[4], 4)]);
+        assert_eq!((!set).entities(&entities).count(), 4);
+    }
+
+    #[test]
+    fn join_get() {
+        struct Position(i32);
+        struct Static;
+        let mut entities = Entities::default();
+        let mut positions = Components::<Position>::default();
+        let mut statics = Components::<Static>::default();
+        let e1 = entities.create();
+        let e2 = entities.create();
+        let e3 = entities.create();
+        positions.insert(e1, Position(1));
+        positions.insert(e2, Position(2));
+        statics.insert(e2, Static);
+
+        // A collision between e1 and e2: only move the one that isn't static.
+        for e in [e1, e2] {
+            if let Some((p, ())) = (&mut positions, Not(&statics)).join_get(&entities, e) {
+                p.0 += 10;
+            }
+        }
+        assert_eq!(positions.get(e1).unwrap().0, 11);
+        assert_eq!(positions.get(e2).unwrap().0, 2);
+        assert!(positions.join_get(&entities, e3).is_none());
+
+        entities.kill(e1);
+        let e4 = entities.create();
+        assert!(positions.join_get(&entities, e1).is_none());
+        assert!((&positions, Maybe(&statics)).join_get(&entities, e4).is_none());
+        assert_eq!((&entities, &positions).join_get(&entities, e2).map(|(e, p)| (e, p.0)), Some((e2, 2)));
+
+        // The inherent `get` of a storage is not shadowed for `&mut` bindings.
+        fn read(s: &mut Components<Position>, e: Entity) -> Option<i32> {
+            s.get(e).map(|p| p.0)
+        }
+        assert_eq!(read(&mut positions, e2), Some(2));
+    }
+
+    #[test]
+    fn join_chunks() {
+        struct Position(u32);
+        struct Velocity(u32);
+        let mut entities = Entities::default();
+        let mut positions = Components::<Position>::default();
+        let mut velocities = Components::<Velocity>::default();
+        for i in 0..600 {
+            let e = entities.create();
+            positions.insert(e, Position(0));
+            if i % 2 == 0 && !(256..512).contains(&i) {
+                velocities.insert(e, Velocity(i));
+            }
+        }
+        let mut bases = vec![];
+        (&mut positions, &velocities)
+            .join_chunks()
+            .for_each(|(base, mask, (p, v))| {
+                bases.push(base);
+                assert_eq!(p.len(), 256.min(600 - base));
+                for i in 0..p.len().min(v.len()) {
+                    if mask[i / 32] & (1 << (i % 32)) != 0 {
+                        p[i].as_mut().unwrap().0 += v[i].as_ref().unwrap().0;
+                    }
+                }
+            });
+        assert_eq!(bases, vec![0, 512]);
+        assert!(join!(&positions && &velocities).all(|(p, v)| p.0 == v.0));
+        assert_eq!(join!(&positions).map(|p| p.0).sum::<u32>(), (0..600).filter(|i| i % 2 == 0 && !(256..512).contains(i)).sum::<u32>());
+    }
+
+    #[test]
+    fn count_and_any() {
+        struct A;
+        struct B;
+        let mut entities = Entities::default();
+        let mut a = Components::<A>::default();
+        let mut b = Components::<B>::default();
+        for i in 0..1000 {
+            let e = entities.create();
+            a.insert(e, A);
+            if i % 4 == 0 {
+                b.insert(e, B);
+            }
+        }
+        assert_eq!(join_count!(&a && !&b), 750);
+        assert_eq!(join_count!(&a && &b), join!(&a && &b).count());
+        assert!(join_any!(&a && &b));
+        assert!(!join_any!(&b && !&a));
+        let mut query = Query::new();
+        assert_eq!(join_count!(query => &entities && &b), 250);
+        assert!(join_any!(query => &entities && &b));
+        assert!(!query.is_empty());
+        assert_eq!(query.count(), 250);
+    }
+
+    #[test]
+    fn planned_order_same_result() {
+        struct Huge;
+        struct Tiny;
+        struct Other;
+        let mut entities = Entities::default();
+        let mut huge = Components::<Huge>::default();
+        let mut tiny = Components::<Tiny>::default();
+        let mut other = Components::<Other>::default();
+        for i in 0..5000 {
+            let e = entities.create();
+            huge.insert(e, Huge);
+            if i % 1000 == 7 {
+                tiny.insert(e, Tiny);
+            }
+            if i % 2000 == 7 {
+                other.insert(e, Other);
+            }
+        }
+        assert_eq!(join_count!(&huge && &tiny), 5);
+        assert_eq!(join_count!(&tiny && &huge), 5);
+        assert_eq!(join_count!(&huge && !&other && &tiny), 2);
+        assert_eq!(join_count!(!&other && &huge && &tiny), 2);
+        assert_eq!(join_count!((&other || &tiny) && &huge && &entities), 5);
+        let matched = join!(&entities && &huge && &tiny && !&other)
+            .map(|(e, _, _)| e.index())
+            .collect::<Vec<_>>();
+        assert_eq!(matched, vec![1007, 3007]);
+    }
+
     #[test]
     fn start_with_not() {
         struct A;
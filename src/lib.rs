@@ -7,17 +7,41 @@ pub use bitset_core::BitSet;
 pub use itertools::izip;
 
 mod bitset;
+mod changes;
 mod component_iterator;
 mod components;
 mod entities;
 mod entity_iterator;
 mod entity;
+mod entity_set;
+mod hierarchy;
+mod indexed;
 mod join;
+mod multi;
+#[cfg(feature = "rayon")]
+mod parallel;
+mod query;
+mod relations;
+mod shared;
+mod sorted;
+mod weak;
 
 pub use self::bitset::*;
+pub use self::changes::*;
 pub use self::component_iterator::*;
 pub use self::components::*;
 pub use self::entities::*;
 pub use self::entity_iterator::*;
 pub use self::entity::*;
+pub use self::entity_set::*;
+pub use self::hierarchy::*;
+pub use self::indexed::*;
 pub use self::join::*;
+pub use self::multi::*;
+#[cfg(feature = "rayon")]
+pub use self::parallel::*;
+pub use self::query::*;
+pub use self::relations::*;
+pub use self::shared::*;
+pub use self::sorted::*;
+pub use self::weak::*;
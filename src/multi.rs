@@ -0,0 +1,144 @@
+use crate::{register_cleanup, Components, Entity, LayeredBitSet};
+
+/// Holds any amount of components of a given type per `Entity`.
+/// The bitset indicates which entities have at least one component.
+///
+/// When joined using `join!`, the components of each matched entity are
+/// returned as a slice.
+pub struct MultiComponents<T> {
+    components: Components<Vec<T>>,
+}
+
+impl<T: 'static> Default for MultiComponents<T> {
+    fn default() -> Self {
+        register_cleanup::<Self>(|me, e| {
+            me.remove(e);
+        });
+        Self {
+            components: Components::default(),
+        }
+    }
+}
+
+impl<T> MultiComponents<T> {
+    /// Adds a component to the given `Entity`.
+    pub fn push(&mut self, entity: Entity, component: T) {
+        if let Some(list) = self.components.get_mut(entity) {
+            list.push(component);
+        } else {
+            self.components.insert(entity, vec![component]);
+        }
+    }
+    /// Removes the component at position `index` in the components of `Entity`.
+    /// Returns `None` if there is no component at this position.
+    pub fn remove_one(&mut self, entity: Entity, index: usize) -> Option<T> {
+        let list = self.components.get_mut(entity)?;
+        if index >= list.len() {
+            return None;
+        }
+        let removed = list.remove(index);
+        if list.is_empty() {
+            self.components.remove(entity);
+        }
+        Some(removed)
+    }
+    /// Removes all the components of `Entity`.
+    /// Returns an empty `Vec` if the entity did not have any component.
+    pub fn remove(&mut self, entity: Entity) -> Vec<T> {
+        self.components.remove(entity).unwrap_or_default()
+    }
+    /// Gets the components of `Entity`.
+    pub fn get_all(&self, entity: Entity) -> &[T] {
+        self.components
+            .get(entity)
+            .map(|l| l.as_slice())
+            .unwrap_or(&[])
+    }
+    /// Gets the components of `Entity` mutably.
+    pub fn get_all_mut(&mut self, entity: Entity) -> &mut [T] {
+        self.components
+            .get_mut(entity)
+            .map(|l| l.as_mut_slice())
+            .unwrap_or(&mut [])
+    }
+    /// Iterates immutably over the components of each entity having at least
+    /// one.
+    pub fn iter_all(&self) -> impl Iterator<Item = &[T]> {
+        self.components.iter().map(|l| l.as_slice())
+    }
+    /// Iterates mutably over the components of each entity having at least
+    /// one.
+    pub fn iter_all_mut(&mut self) -> impl Iterator<Item = &mut [T]> {
+        self.components.iter_mut().map(|l| l.as_mut_slice())
+    }
+    /// Same as `iter_all`. Used by `join!`.
+    pub fn iter(&self) -> impl Iterator<Item = &[T]> {
+        self.iter_all()
+    }
+    /// Same as `iter_all_mut`. Used by `join!`.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut [T]> {
+        self.iter_all_mut()
+    }
+    /// Iterates immutably over the components of the entities where `bitset`
+    /// indicates the indices of entities.
+    pub fn iter_with_bitset(
+        &self,
+        bitset: impl Into<std::rc::Rc<LayeredBitSet>>,
+    ) -> impl Iterator<Item = Option<&[T]>> {
+        self.components
+            .iter_with_bitset(bitset)
+            .map(|l| l.map(|l| l.as_slice()))
+    }
+    /// Iterates mutably over the components of the entities where `bitset`
+    /// indicates the indices of entities.
+    pub fn iter_mut_with_bitset(
+        &mut self,
+        bitset: impl Into<std::rc::Rc<LayeredBitSet>>,
+    ) -> impl Iterator<Item = Option<&mut [T]>> {
+        self.components
+            .iter_mut_with_bitset(bitset)
+            .map(|l| l.map(|l| l.as_mut_slice()))
+    }
+    /// Returns the bitset indicating which entity indices have at least one
+    /// component associated to them.
+    pub fn bitset(&self) -> &LayeredBitSet {
+        self.components.bitset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    #[test]
+    fn multiple_components_per_entity() {
+        #[derive(Debug, PartialEq)]
+        struct Buff(u32);
+        struct Health(u32);
+
+        let mut entities = Entities::default();
+        let e1 = entities.create();
+        let e2 = entities.create();
+        let e3 = entities.create();
+
+        let mut buffs = MultiComponents::<Buff>::default();
+        let mut healths = Components::<Health>::default();
+        buffs.push(e1, Buff(1));
+        buffs.push(e1, Buff(2));
+        buffs.push(e2, Buff(3));
+        healths.insert(e1, Health(0));
+        healths.insert(e3, Health(0));
+        assert_eq!(buffs.get_all(e1), &[Buff(1), Buff(2)]);
+        assert_eq!(buffs.iter_all().count(), 2);
+
+        join!(&buffs && &mut healths).for_each(|(buffs, health)| {
+            health.0 = buffs.iter().map(|b| b.0).sum();
+        });
+        assert_eq!(healths.get(e1).unwrap().0, 3);
+
+        assert_eq!(buffs.remove_one(e1, 0), Some(Buff(1)));
+        assert_eq!(buffs.remove_one(e2, 0), Some(Buff(3)));
+        assert_eq!(buffs.remove_one(e2, 0), None);
+        assert!(buffs.get_all(e2).is_empty());
+        assert_eq!(join!(&buffs).count(), 1);
+    }
+}
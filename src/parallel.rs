@@ -0,0 +1,339 @@
+use crate::{Entity, LayeredBitSet, TicksPtr};
+
+use rayon::prelude::*;
+use std::marker::PhantomData;
+
+/// Iterates over the indices of the bits set in a single 256 bits block of
+/// a bitset.
+pub struct BlockIndices {
+    block: [u32; 8],
+    base: usize,
+    word: usize,
+    current: u32,
+}
+
+impl BlockIndices {
+    pub(crate) fn new(bitset: &LayeredBitSet, block: usize) -> Self {
+        BlockIndices {
+            block: bitset.blocks()[block],
+            base: block * 256,
+            word: 0,
+            current: bitset.blocks()[block][0],
+        }
+    }
+}
+
+impl Iterator for BlockIndices {
+    type Item = usize;
+    fn next(&mut self) -> Option<usize> {
+        while self.current == 0 {
+            self.word += 1;
+            if self.word >= 8 {
+                return None;
+            }
+            self.current = self.block[self.word];
+        }
+        let bit = self.current.trailing_zeros() as usize;
+        // Clears the lowest set bit.
+        self.current &= self.current - 1;
+        Some(self.base + self.word * 32 + bit)
+    }
+}
+
+/// A storage that can be split in 256 entities blocks, one per thread.
+/// Used by `par_join!`.
+#[doc(hidden)]
+pub trait ParView: Send + Sync {
+    type Item;
+    type Iter: Iterator<Item = Self::Item>;
+    /// Iterates over the values of this view for the indices set in
+    /// `bitset[block]`.
+    ///
+    /// # Safety
+    /// A block must not be accessed by two iterators at the same time, as
+    /// mutable views hand out `&mut` references.
+    unsafe fn block(&self, block: usize, bitset: &LayeredBitSet) -> Self::Iter;
+}
+
+/// Immutable parallel view over the components of a `Components<T>`.
+pub struct ParComponents<'a, T> {
+    pub(crate) storage: &'a [Option<T>],
+}
+
+/// Mutable parallel view over the components of a `Components<T>`.
+pub struct ParComponentsMut<'a, T> {
+    pub(crate) storage: *mut Option<T>,
+    pub(crate) ticks: TicksPtr,
+    pub(crate) len: usize,
+    pub(crate) _phantom: PhantomData<&'a mut [Option<T>]>,
+}
+
+// Safe: each block, and thus each component, is only accessed by one thread.
+unsafe impl<'a, T: Send> Send for ParComponentsMut<'a, T> {}
+unsafe impl<'a, T: Send> Sync for ParComponentsMut<'a, T> {}
+
+/// Parallel view over the alive entities of `Entities`.
+pub struct ParEntities<'a> {
+    pub(crate) alive: &'a LayeredBitSet,
+    pub(crate) generations: &'a [u32],
+    pub(crate) next_id: usize,
+}
+
+/// Iterator over a block of `ParComponents`.
+pub struct ParComponentsIter<'a, T> {
+    indices: BlockIndices,
+    storage: &'a [Option<T>],
+}
+
+impl<'a, T> Iterator for ParComponentsIter<'a, T> {
+    type Item = Option<&'a T>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.indices
+            .next()
+            .map(|i| self.storage.get(i).and_then(|c| c.as_ref()))
+    }
+}
+
+/// Iterator over a block of `ParComponentsMut`.
+pub struct ParComponentsMutIter<'a, T> {
+    indices: BlockIndices,
+    storage: *mut Option<T>,
+    ticks: TicksPtr,
+    len: usize,
+    _phantom: PhantomData<&'a mut [Option<T>]>,
+}
+
+impl<'a, T> Iterator for ParComponentsMutIter<'a, T> {
+    type Item = Option<&'a mut T>;
+    fn next(&mut self) -> Option<Self::Item> {
+        // Unsafe: indices are checked against the storage length and each
+        // one is yielded at most once.
+        self.indices.next().map(|i| {
+            if i < self.len {
+                let component = unsafe { (*self.storage.add(i)).as_mut() };
+                if component.is_some() {
+                    // Each block is iterated by a single thread.
+                    unsafe { self.ticks.mark_changed(i) };
+                }
+                component
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Iterator over a block of `ParEntities`.
+pub struct ParEntitiesIter<'a> {
+    indices: BlockIndices,
+    next_id: usize,
+    alive: &'a LayeredBitSet,
+    generations: &'a [u32],
+}
+
+impl<'a> Iterator for ParEntitiesIter<'a> {
+    type Item = Option<Entity>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.indices.next().map(|i| {
+            if i < self.next_id && self.alive.bit_test(i) {
+                Some(Entity::new(i as u32, self.generations[i]))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+impl<'a, T: Sync> ParView for ParComponents<'a, T> {
+    type Item = Option<&'a T>;
+    type Iter = ParComponentsIter<'a, T>;
+    unsafe fn block(&self, block: usize, bitset: &LayeredBitSet) -> Self::Iter {
+        ParComponentsIter {
+            indices: BlockIndices::new(bitset, block),
+            storage: self.storage,
+        }
+    }
+}
+
+impl<'a, T: Send> ParView for ParComponentsMut<'a, T> {
+    type Item = Option<&'a mut T>;
+    type Iter = ParComponentsMutIter<'a, T>;
+    unsafe fn block(&self, block: usize, bitset: &LayeredBitSet) -> Self::Iter {
+        ParComponentsMutIter {
+            indices: BlockIndices::new(bitset, block),
+            storage: self.storage,
+            ticks: self.ticks,
+            len: self.len,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<'a> ParView for ParEntities<'a> {
+    type Item = Option<Entity>;
+    type Iter = ParEntitiesIter<'a>;
+    unsafe fn block(&self, block: usize, bitset: &LayeredBitSet) -> Self::Iter {
+        ParEntitiesIter {
+            indices: BlockIndices::new(bitset, block),
+            next_id: self.next_id,
+            alive: self.alive,
+            generations: self.generations,
+        }
+    }
+}
+
+/// Unwraps the values of a view over a storage required by the join.
+#[doc(hidden)]
+pub struct ParRequired<V>(pub V);
+
+impl<V: ParView<Item = Option<T>>, T> ParView for ParRequired<V> {
+    type Item = T;
+    type Iter = std::iter::Map<V::Iter, fn(Option<T>) -> T>;
+    unsafe fn block(&self, block: usize, bitset: &LayeredBitSet) -> Self::Iter {
+        self.0.block(block, bitset).map(Option::unwrap)
+    }
+}
+
+/// The view of a join made only of negated terms, which yields `()` for
+/// each matched entity.
+impl ParView for () {
+    type Item = ();
+    type Iter = std::iter::Map<BlockIndices, fn(usize)>;
+    unsafe fn block(&self, block: usize, bitset: &LayeredBitSet) -> Self::Iter {
+        BlockIndices::new(bitset, block).map(|_| ())
+    }
+}
+
+macro_rules! impl_par_view_tuple {
+    ($($v:ident),*) => {
+        impl<$($v: ParView),*> ParView for ($($v,)*) {
+            type Item = ($($v::Item,)*);
+            type Iter = itertools::Zip<($($v::Iter,)*)>;
+            unsafe fn block(&self, block: usize, bitset: &LayeredBitSet) -> Self::Iter {
+                #[allow(non_snake_case)]
+                let ($($v,)*) = self;
+                itertools::multizip(($($v.block(block, bitset),)*))
+            }
+        }
+    };
+}
+
+impl_par_view_tuple!(A, B);
+impl_par_view_tuple!(A, B, C);
+impl_par_view_tuple!(A, B, C, D);
+impl_par_view_tuple!(A, B, C, D, E);
+impl_par_view_tuple!(A, B, C, D, E, F);
+impl_par_view_tuple!(A, B, C, D, E, F, G);
+impl_par_view_tuple!(A, B, C, D, E, F, G, H);
+
+/// Splits the iteration over `views` in blocks of 256 entities which are
+/// processed in parallel.
+#[doc(hidden)]
+pub fn par_join_views<V: ParView>(bitset: LayeredBitSet, views: V) -> impl ParallelIterator<Item = V::Item>
+where
+    V::Item: Send,
+{
+    bitset
+        .non_empty_blocks()
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        // Unsafe: each block index is visited exactly once.
+        .flat_map_iter(move |block| unsafe { views.block(block, &bitset) })
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! par_views {
+    ($(,)?$($views:block),* ;) => {($($views),*)};
+    ($(,)?$($views:block),* ; [req &mut $st:ident] $($tail:tt)*) => {
+        par_views!($($views),* , {$crate::ParRequired($st.par_view_mut())} ; $($tail)*)
+    };
+    ($(,)?$($views:block),* ; [req &$st:ident] $($tail:tt)*) => {
+        par_views!($($views),* , {$crate::ParRequired($st.par_view())} ; $($tail)*)
+    };
+    ($(,)?$($views:block),* ; [opt &mut $st:ident] $($tail:tt)*) => {
+        par_views!($($views),* , {$st.par_view_mut()} ; $($tail)*)
+    };
+    ($(,)?$($views:block),* ; [opt &$st:ident] $($tail:tt)*) => {
+        par_views!($($views),* , {$st.par_view()} ; $($tail)*)
+    };
+    ($(,)?$($views:block),* ; [not $($term:tt)*] $($tail:tt)*) => {
+        par_views!($($views),* ; $($tail)*)
+    };
+}
+
+/// The parallel version of `join!`, available with the `rayon` feature.
+///
+/// Accepts the same syntax as `join!` and yields the same items, but returns
+/// a rayon `ParallelIterator`. The work is split in blocks of 256 entities,
+/// so each component is accessed by only one thread.
+/// ```rust,ignore
+/// par_join!(&mut storage1 && &storage2)
+///     .for_each(|(component1, component2)| {});
+/// ```
+#[macro_export]
+macro_rules! par_join {
+    (&$st:ident) => {
+        $st.par_iter()
+    };
+    (&mut $st:ident) => {
+        $st.par_iter_mut()
+    };
+    ($($complex:tt)*) => {
+        {
+            let bound = bitset_bound!(0usize; $($complex)*);
+            let mut bitset = std::rc::Rc::new($crate::LayeredBitSet::default());
+            gen_bitset!(bitset, bound; $($complex)*);
+            let bitset = std::rc::Rc::try_unwrap(bitset).unwrap();
+            $crate::par_join_views(bitset, join_terms!(par_views!(); $($complex)*))
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use rayon::prelude::*;
+    #[test]
+    fn par_join_matches_join() {
+        struct A(u32);
+        struct B(u32);
+        let mut entities = Entities::default();
+        let mut storage1 = Components::<A>::default().with_change_tracking();
+        let mut storage2 = Components::<B>::default();
+        for i in 0..2000 {
+            let e = entities.create();
+            if i % 3 == 0 {
+                storage1.insert(e, A(i));
+            }
+            if i % 7 != 0 {
+                storage2.insert(e, B(i));
+            }
+        }
+
+        let tick = storage1.tick();
+        par_join!(&mut storage1 && &storage2)
+            .for_each(|(a, b)| a.0 += b.0);
+        assert_eq!(storage1.changed(tick).count(), 667 - 96);
+        let seq = join!(&entities && &storage1 && &storage2)
+            .map(|(e, a, b)| (e, a.0, b.0))
+            .collect::<Vec<_>>();
+        let par = par_join!(&entities && &storage1 && &storage2)
+            .map(|(e, a, b)| (e, a.0, b.0))
+            .collect::<Vec<_>>();
+        assert_eq!(seq, par);
+        assert!(seq.iter().all(|(_, a, b)| a == &(b * 2)));
+
+        let seq = join!(&storage1 || !&storage2).count();
+        let par = par_join!(&storage1 || !&storage2).count();
+        assert_eq!(seq, par);
+        assert_eq!(par, 667 + 286 - 96);
+
+        let seq = join!(!&storage1 && !&storage2).count();
+        assert_eq!(seq, 286 - 96);
+        assert_eq!(par_join!(!&storage1 && !&storage2).count(), seq);
+
+        par_join!(&mut storage1).for_each(|a| a.0 = 0);
+        assert_eq!(par_join!(&storage1).map(|a| a.0).sum::<u32>(), 0);
+    }
+}
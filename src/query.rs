@@ -0,0 +1,105 @@
+use crate::LayeredBitSet;
+
+use std::rc::Rc;
+
+/// A cache for the bitset of a `join!`, reused across calls.
+///
+/// The bitset is only recomputed when one of the joined storages changed
+/// since the last call, which is detected using the version of their bitsets.
+/// A `Query` should always be used with the same join expression.
+///
+/// ```rust,ignore
+/// let mut query = Query::default();
+/// // Each frame:
+/// join!(query => &mut storage1 && &storage2).for_each(|(c1, c2)| {});
+/// ```
+#[derive(Default)]
+pub struct Query {
+    bitset: Rc<LayeredBitSet>,
+    expression: &'static str,
+    versions: Vec<(u64, u64)>,
+}
+
+impl Query {
+    /// Creates a new empty `Query`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Returns true if the cached bitset was computed for another expression
+    /// or from other storage versions, and remembers the new ones.
+    #[doc(hidden)]
+    pub fn is_outdated(&mut self, expression: &'static str, versions: &[(u64, u64)]) -> bool {
+        if self.expression == expression && self.versions == versions {
+            false
+        } else {
+            self.expression = expression;
+            self.versions.clear();
+            self.versions.extend_from_slice(versions);
+            true
+        }
+    }
+    /// Takes the cached bitset out to update it.
+    /// If it is still used by an iterator, a new one is allocated.
+    #[doc(hidden)]
+    pub fn take_bitset(&mut self) -> Rc<LayeredBitSet> {
+        let bitset = std::mem::take(&mut self.bitset);
+        if Rc::strong_count(&bitset) == 1 {
+            bitset
+        } else {
+            Rc::default()
+        }
+    }
+    /// Puts back the updated bitset.
+    #[doc(hidden)]
+    pub fn set_bitset(&mut self, bitset: Rc<LayeredBitSet>) {
+        self.bitset = bitset;
+    }
+    /// Returns the cached bitset.
+    pub fn bitset(&self) -> &Rc<LayeredBitSet> {
+        &self.bitset
+    }
+    /// Returns how many entities were matched by the last join, from the
+    /// cached bitset.
+    pub fn count(&self) -> usize {
+        self.bitset.bit_count()
+    }
+    /// Returns true if the last join matched no entity.
+    pub fn is_empty(&self) -> bool {
+        self.bitset.is_empty()
+    }
+    /// Forgets the cached bitset, forcing it to be recomputed on the next call.
+    pub fn invalidate(&mut self) {
+        self.versions.clear();
+        self.expression = "";
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    #[test]
+    fn query_reuses_bitset() {
+        struct A(u32);
+        struct B;
+        let mut entities = Entities::default();
+        let mut comp1 = Components::<A>::default();
+        let mut comp2 = Components::<B>::default();
+        let e1 = entities.create();
+        let e2 = entities.create();
+        comp1.insert(e1, A(0));
+        comp1.insert(e2, A(0));
+        comp2.insert(e1, B);
+
+        let mut query = Query::new();
+        join!(query => &mut comp1 && &comp2).for_each(|(a, _)| a.0 += 1);
+        let version = query.bitset().version();
+        join!(query => &mut comp1 && &comp2).for_each(|(a, _)| a.0 += 1);
+        assert_eq!(query.bitset().version(), version);
+        assert_eq!(comp1.get(e1).unwrap().0, 2);
+
+        comp2.insert(e2, B);
+        assert_eq!(join!(query => &mut comp1 && &comp2).count(), 2);
+        assert_ne!(query.bitset().version(), version);
+        assert_eq!(join!(query => &entities && !&comp2).count(), 0);
+    }
+}
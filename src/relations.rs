@@ -0,0 +1,255 @@
+use crate::{register_cleanup, Components, Entity};
+
+/// The targets of a source `Entity` in `Relations`, with the data of each
+/// relation, in insertion order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Outgoing<R> {
+    /// The entity the relations start from.
+    pub source: Entity,
+    pub targets: Vec<(Entity, R)>,
+}
+
+/// The sources pointing to a target `Entity` in `Relations`, in insertion
+/// order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Incoming {
+    /// The entity the relations point to.
+    pub target: Entity,
+    pub sources: Vec<Entity>,
+}
+
+/// Typed relations from a source `Entity` to a target `Entity`, such as
+/// `Targets` or `DockedAt`, each holding a value of type `R`.
+///
+/// Relations are indexed in both directions, so that both "what does this
+/// entity target?" and "who targets this entity?" are cheap. An entity can
+/// be the source of many relations and the target of many others.
+///
+/// Endpoints are matched by `Entity`, generation included, so a stale
+/// `Entity` never sees nor removes the relations of a newer entity reusing
+/// its index. As in `Components`, inserting a relation for an entity drops
+/// the relations of any other entity with the same index.
+///
+/// The `Outgoing` and `Incoming` storages can be borrowed to join over the
+/// sources and the targets. The killed entities cleanup removes all the
+/// relations of killed entities, in both directions.
+/// ```rust,ignore
+/// struct Targets;
+/// let mut targets = Relations::<Targets>::default();
+/// targets.insert(turret, ship, Targets);
+/// join!(&entities && &ships).for_each(|(ship, _)| {
+///     let attackers = targets.sources(ship).len();
+/// });
+/// ```
+pub struct Relations<R> {
+    outgoing: Components<Outgoing<R>>,
+    incoming: Components<Incoming>,
+}
+
+impl<R: 'static> Default for Relations<R> {
+    fn default() -> Self {
+        register_cleanup::<Self>(|me, e| {
+            me.remove_entity(e);
+        });
+        Self {
+            outgoing: Components::default(),
+            incoming: Components::default(),
+        }
+    }
+}
+
+impl<R> Relations<R> {
+    /// Relates `source` to `target`.
+    /// Returns the previous value of this relation, if any.
+    pub fn insert(&mut self, source: Entity, target: Entity, relation: R) -> Option<R> {
+        if let Some(existing) = self.get_mut(source, target) {
+            return Some(std::mem::replace(existing, relation));
+        }
+        // The slots may still hold older entities with the same indices.
+        if let Some(other) = self.outgoing.get(source).map(|o| o.source).filter(|o| *o != source) {
+            self.remove_entity(other);
+        }
+        if let Some(other) = self.incoming.get(target).map(|i| i.target).filter(|i| *i != target) {
+            self.remove_entity(other);
+        }
+        if let Some(outgoing) = self.outgoing_mut(source) {
+            outgoing.targets.push((target, relation));
+        } else {
+            let targets = vec![(target, relation)];
+            self.outgoing.insert(source, Outgoing { source, targets });
+        }
+        if let Some(incoming) = self.incoming_mut(target) {
+            incoming.sources.push(source);
+        } else {
+            let sources = vec![source];
+            self.incoming.insert(target, Incoming { target, sources });
+        }
+        None
+    }
+    /// Removes the relation from `source` to `target`.
+    /// Returns its value, if any.
+    pub fn remove(&mut self, source: Entity, target: Entity) -> Option<R> {
+        let outgoing = self.outgoing_mut(source)?;
+        let position = outgoing.targets.iter().position(|(t, _)| *t == target)?;
+        let (_, relation) = outgoing.targets.remove(position);
+        if outgoing.targets.is_empty() {
+            self.outgoing.remove(source);
+        }
+        if let Some(incoming) = self.incoming_mut(target) {
+            incoming.sources.retain(|s| *s != source);
+            if incoming.sources.is_empty() {
+                self.incoming.remove(target);
+            }
+        }
+        Some(relation)
+    }
+    /// Removes all the relations from and to `entity`.
+    pub fn remove_entity(&mut self, entity: Entity) {
+        if self.outgoing_of(entity).is_some() {
+            for (target, _) in self.outgoing.remove(entity).into_iter().flat_map(|o| o.targets) {
+                if let Some(incoming) = self.incoming_mut(target) {
+                    incoming.sources.retain(|s| *s != entity);
+                    if incoming.sources.is_empty() {
+                        self.incoming.remove(target);
+                    }
+                }
+            }
+        }
+        if self.incoming_of(entity).is_some() {
+            for source in self.incoming.remove(entity).into_iter().flat_map(|i| i.sources) {
+                if let Some(outgoing) = self.outgoing_mut(source) {
+                    outgoing.targets.retain(|(t, _)| *t != entity);
+                    if outgoing.targets.is_empty() {
+                        self.outgoing.remove(source);
+                    }
+                }
+            }
+        }
+    }
+    /// Gets an immutable reference to the value of the relation from
+    /// `source` to `target`.
+    pub fn get(&self, source: Entity, target: Entity) -> Option<&R> {
+        self.outgoing_of(source)?
+            .targets
+            .iter()
+            .find(|(t, _)| *t == target)
+            .map(|(_, r)| r)
+    }
+    /// Gets a mutable reference to the value of the relation from `source`
+    /// to `target`.
+    pub fn get_mut(&mut self, source: Entity, target: Entity) -> Option<&mut R> {
+        self.outgoing_mut(source)?
+            .targets
+            .iter_mut()
+            .find(|(t, _)| *t == target)
+            .map(|(_, r)| r)
+    }
+    /// Iterates over the targets of `source`, with the value of each relation.
+    pub fn targets(&self, source: Entity) -> impl Iterator<Item = (Entity, &R)> {
+        self.outgoing_of(source)
+            .into_iter()
+            .flat_map(|o| o.targets.iter().map(|(t, r)| (*t, r)))
+    }
+    /// Returns the sources relating to `target`.
+    pub fn sources(&self, target: Entity) -> &[Entity] {
+        self.incoming_of(target).map(|i| &i.sources[..]).unwrap_or(&[])
+    }
+    /// Returns the storage of the relations by source, to join over.
+    pub fn outgoing(&self) -> &Components<Outgoing<R>> {
+        &self.outgoing
+    }
+    /// Returns the storage of the sources by target, to join over.
+    pub fn incoming(&self) -> &Components<Incoming> {
+        &self.incoming
+    }
+    fn outgoing_of(&self, source: Entity) -> Option<&Outgoing<R>> {
+        self.outgoing.get(source).filter(|o| o.source == source)
+    }
+    fn outgoing_mut(&mut self, source: Entity) -> Option<&mut Outgoing<R>> {
+        self.outgoing.get_mut(source).filter(|o| o.source == source)
+    }
+    fn incoming_of(&self, target: Entity) -> Option<&Incoming> {
+        self.incoming.get(target).filter(|i| i.target == target)
+    }
+    fn incoming_mut(&mut self, target: Entity) -> Option<&mut Incoming> {
+        self.incoming.get_mut(target).filter(|i| i.target == target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    #[test]
+    fn relations_both_directions() {
+        use atomic_refcell_try::AtomicRefCell;
+        use std::any::Any;
+
+        #[derive(Debug, PartialEq)]
+        struct Targets(u32);
+        let mut entities = Entities::default();
+        let mut relations = Relations::<Targets>::default();
+        let e = (0..4).map(|_| entities.create()).collect::<Vec<_>>();
+        relations.insert(e[0], e[2], Targets(1));
+        relations.insert(e[1], e[2], Targets(2));
+        relations.insert(e[1], e[3], Targets(3));
+        assert_eq!(relations.insert(e[1], e[3], Targets(4)), Some(Targets(3)));
+
+        assert_eq!(relations.sources(e[2]), &[e[0], e[1]]);
+        assert_eq!(relations.targets(e[1]).map(|(t, r)| (t, r.0)).collect::<Vec<_>>(), vec![(e[2], 2), (e[3], 4)]);
+        assert_eq!(relations.get(e[0], e[2]), Some(&Targets(1)));
+        assert_eq!(relations.get(e[2], e[0]), None);
+        let targeted = (&entities, relations.incoming()).join().map(|(t, _)| t).collect::<Vec<_>>();
+        assert_eq!(targeted, vec![e[2], e[3]]);
+
+        assert_eq!(relations.remove(e[1], e[3]), Some(Targets(4)));
+        assert!(relations.sources(e[3]).is_empty());
+
+        entities.kill(e[2]);
+        let cell: AtomicRefCell<Box<dyn Any>> = AtomicRefCell::new(Box::new(relations));
+        let any = atomic_refcell_try::AtomicRefMut::map(cell.borrow_mut(), |b| &mut **b);
+        COMPONENT_REGISTRY.lock().unwrap()[&std::any::TypeId::of::<Relations<Targets>>()](
+            any,
+            entities.killed(),
+        );
+        let relations = cell.borrow();
+        let relations = relations.downcast_ref::<Relations<Targets>>().unwrap();
+        assert!(relations.outgoing().bitset().is_empty());
+        assert!(relations.incoming().bitset().is_empty());
+    }
+
+    #[test]
+    fn relations_reused_index() {
+        let mut entities = Entities::default();
+        let mut relations = Relations::<u32>::default();
+        let x = entities.create();
+        let y = entities.create();
+        let z = entities.create();
+        entities.kill(x);
+        entities.kill(z);
+        entities.clear_killed();
+        relations.insert(x, y, 1);
+        let x2 = entities.create();
+        let z2 = entities.create();
+        assert_eq!(x2.index(), x.index());
+        assert_eq!(z2.index(), z.index());
+        relations.insert(y, z2, 2);
+
+        // Stale and recycled entities only see their own relations.
+        assert_eq!(relations.get(x2, y), None);
+        relations.remove_entity(x2);
+        assert_eq!(relations.sources(y), &[x]);
+        assert_eq!(relations.remove(y, z), None);
+        relations.remove_entity(z);
+        assert_eq!(relations.get(y, z2), Some(&2));
+        assert_eq!(relations.sources(z2), &[y]);
+
+        // Inserting for x2 drops the relations left by x.
+        relations.insert(x2, z2, 3);
+        assert!(relations.sources(y).is_empty());
+        assert_eq!(relations.sources(z2), &[y, x2]);
+        relations.remove_entity(z2);
+        relations.remove_entity(y);
+        assert!(relations.outgoing().bitset().is_empty());
+        assert!(relations.incoming().bitset().is_empty());
+    }
+}
@@ -0,0 +1,207 @@
+use crate::{register_cleanup, Components, Entity, LayeredBitSet};
+
+/// A value of a `SharedComponents` along with the entities pointing to it.
+struct SharedValue<T> {
+    value: T,
+    members: Vec<Entity>,
+}
+
+/// Holds components that can be shared between multiple entities.
+/// Each `Entity` points to a single value, and many entities can point to
+/// the same one.
+///
+/// Useful for heavy immutable data like meshes or stat tables.
+/// Mutating the value of one entity through `get_mut` clones it if other
+/// entities share it (copy-on-write).
+pub struct SharedComponents<T> {
+    indices: Components<usize>,
+    values: Vec<Option<SharedValue<T>>>,
+    free: Vec<usize>,
+}
+
+impl<T: 'static> Default for SharedComponents<T> {
+    fn default() -> Self {
+        register_cleanup::<Self>(|me, e| {
+            me.remove(e);
+        });
+        Self {
+            indices: Components::default(),
+            values: vec![],
+            free: vec![],
+        }
+    }
+}
+
+impl<T> SharedComponents<T> {
+    /// Stores a new value and returns its slot.
+    fn alloc(&mut self, value: T) -> usize {
+        let shared = Some(SharedValue {
+            value,
+            members: vec![],
+        });
+        if let Some(slot) = self.free.pop() {
+            self.values[slot] = shared;
+            slot
+        } else {
+            self.values.push(shared);
+            self.values.len() - 1
+        }
+    }
+    /// Makes `entity` point to the value in `slot`.
+    /// Returns the previous value of the entity if no other entity was using it.
+    fn attach(&mut self, entity: Entity, slot: usize) -> Option<T> {
+        let old = self.remove(entity);
+        self.indices.insert(entity, slot);
+        self.values[slot].as_mut().unwrap().members.push(entity);
+        old
+    }
+    /// Inserts a value used only by the given `Entity`.
+    /// Returns the previous value of the entity if no other entity was using it.
+    pub fn insert(&mut self, entity: Entity, value: T) -> Option<T> {
+        let slot = self.alloc(value);
+        self.attach(entity, slot)
+    }
+    /// Inserts a single value shared by all of the given entities.
+    pub fn set_shared(&mut self, entities: impl IntoIterator<Item = Entity>, value: T) {
+        let slot = self.alloc(value);
+        for e in entities {
+            // Attaching an entity twice would free the slot being filled.
+            if self.indices.get(e) != Some(&slot) {
+                self.attach(e, slot);
+            }
+        }
+        // Nobody ended up using the value.
+        if self.values[slot].as_ref().unwrap().members.is_empty() {
+            self.values[slot] = None;
+            self.free.push(slot);
+        }
+    }
+    /// Makes `entity` share the value of `source`.
+    /// Returns false if `source` has no value.
+    pub fn share(&mut self, source: Entity, entity: Entity) -> bool {
+        if let Some(slot) = self.indices.get(source).copied() {
+            if self.indices.get(entity) != Some(&slot) {
+                self.attach(entity, slot);
+            }
+            true
+        } else {
+            false
+        }
+    }
+    /// Gets an immutable reference to the value of `Entity`.
+    pub fn get(&self, entity: Entity) -> Option<&T> {
+        self.indices
+            .get(entity)
+            .map(|slot| &self.values[*slot].as_ref().unwrap().value)
+    }
+    /// Gets a mutable reference to the value of `Entity`.
+    /// If the value is shared with other entities, it is first cloned so that
+    /// only this `Entity` sees the modification.
+    pub fn get_mut(&mut self, entity: Entity) -> Option<&mut T>
+    where
+        T: Clone,
+    {
+        let slot = *self.indices.get(entity)?;
+        let slot = if self.values[slot].as_ref().unwrap().members.len() > 1 {
+            let value = self.values[slot].as_ref().unwrap().value.clone();
+            let new = self.alloc(value);
+            self.attach(entity, new);
+            new
+        } else {
+            slot
+        };
+        Some(&mut self.values[slot].as_mut().unwrap().value)
+    }
+    /// Returns how many entities point to the value of `Entity`.
+    /// Returns 0 if the entity has no value.
+    pub fn share_count(&self, entity: Entity) -> usize {
+        self.indices
+            .get(entity)
+            .map(|slot| self.values[*slot].as_ref().unwrap().members.len())
+            .unwrap_or(0)
+    }
+    /// Removes the value of `Entity`.
+    /// Returns `Some(T)` if the entity had a value that no other entity uses.
+    /// Returns `None` if the entity had no value or if it was still shared.
+    pub fn remove(&mut self, entity: Entity) -> Option<T> {
+        let slot = self.indices.remove(entity)?;
+        let shared = self.values[slot].as_mut().unwrap();
+        shared.members.retain(|e| e.index() != entity.index());
+        if shared.members.is_empty() {
+            self.free.push(slot);
+            self.values[slot].take().map(|s| s.value)
+        } else {
+            None
+        }
+    }
+    /// Iterates immutably over the value of each entity.
+    /// Shared values are returned once per `Entity` using them.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.indices
+            .iter()
+            .map(move |slot| &self.values[*slot].as_ref().unwrap().value)
+    }
+    /// Iterates over each distinct value along with the entities using it.
+    pub fn iter_grouped(&self) -> impl Iterator<Item = (&T, &[Entity])> {
+        self.values
+            .iter()
+            .flatten()
+            .map(|s| (&s.value, s.members.as_slice()))
+    }
+    /// Iterates immutably over the values of the entities where `bitset`
+    /// indicates the indices of entities.
+    pub fn iter_with_bitset(
+        &self,
+        bitset: impl Into<std::rc::Rc<LayeredBitSet>>,
+    ) -> impl Iterator<Item = Option<&T>> {
+        self.indices.iter_with_bitset(bitset).map(move |slot| {
+            slot.map(|slot| &self.values[*slot].as_ref().unwrap().value)
+        })
+    }
+    /// Returns the bitset indicating which entity indices have a value
+    /// associated to them.
+    pub fn bitset(&self) -> &LayeredBitSet {
+        self.indices.bitset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    #[test]
+    fn shared_copy_on_write() {
+        #[derive(Clone, Debug, PartialEq)]
+        struct Mesh(u32);
+
+        let mut entities = Entities::default();
+        let e1 = entities.create();
+        let e2 = entities.create();
+        let e3 = entities.create();
+
+        let mut storage = SharedComponents::<Mesh>::default();
+        storage.set_shared(vec![e1, e2, e3], Mesh(1));
+        assert_eq!(storage.share_count(e1), 3);
+        assert_eq!(storage.iter_grouped().count(), 1);
+
+        storage.get_mut(e2).unwrap().0 = 2;
+        assert_eq!(storage.get(e1), Some(&Mesh(1)));
+        assert_eq!(storage.get(e2), Some(&Mesh(2)));
+        assert_eq!(storage.get(e3), Some(&Mesh(1)));
+        assert_eq!(storage.share_count(e1), 2);
+
+        // Unshared values are mutated in place.
+        storage.get_mut(e2).unwrap().0 = 3;
+        assert_eq!(storage.iter_grouped().count(), 2);
+
+        assert_eq!(storage.remove(e1), None);
+        assert_eq!(storage.remove(e3), Some(Mesh(1)));
+        assert!(storage.share(e2, e1));
+        let groups = storage.iter_grouped().collect::<Vec<_>>();
+        assert_eq!(groups, vec![(&Mesh(3), &[e2, e1][..])]);
+        assert_eq!(join!(&storage).count(), 2);
+
+        storage.set_shared(vec![e3, e3, e1], Mesh(4));
+        assert_eq!(storage.share_count(e3), 2);
+        assert_eq!(storage.get(e1), Some(&Mesh(4)));
+    }
+}
@@ -0,0 +1,173 @@
+use crate::{Components, Join, Tick};
+
+/// The entity indices having a component of type `T`, sorted by a key
+/// computed from the component.
+///
+/// The order is kept between frames. `update` only sorts the components
+/// that were inserted or modified since the last call, then merges them
+/// back in linear time. Ties are ordered by entity index.
+///
+/// ```rust,ignore
+/// let mut depths = Components::<Depth>::default().with_change_tracking();
+/// let mut draw_order = SortedIndex::new(|depth: &Depth| depth.0);
+/// // Each frame:
+/// draw_order.update(&depths);
+/// draw_order.join((&entities, &sprites)).for_each(|(entity, sprite)| {});
+/// ```
+pub struct SortedIndex<T, K> {
+    key: fn(&T) -> K,
+    order: Vec<(K, u32)>,
+    tick: Tick,
+}
+
+impl<T, K: Ord> SortedIndex<T, K> {
+    /// Creates an empty index ordering components by `key`.
+    pub fn new(key: fn(&T) -> K) -> Self {
+        SortedIndex {
+            key,
+            order: vec![],
+            tick: Tick::default(),
+        }
+    }
+    /// Brings the order up to date with the content of `storage`.
+    /// Should always be called with the same storage, which needs change
+    /// tracking enabled.
+    pub fn update(&mut self, storage: &Components<T>) {
+        let changed = storage.changed(self.tick);
+        let removed = storage.removed(self.tick);
+        self.tick = storage.tick();
+        if changed.is_empty() && removed.is_empty() {
+            return;
+        }
+        self.order.retain(|(_, id)| {
+            let id = *id as usize;
+            !changed.bitset().bit_test(id) && !removed.bitset().bit_test(id)
+        });
+        let key = self.key;
+        let mut current = 0;
+        let ids = std::iter::from_fn(|| {
+            let id = changed.bitset().next_set(current)?;
+            current = id + 1;
+            Some(id as u32)
+        });
+        let mut new = ids
+            .zip(storage.iter_with_bitset(changed.bitset().clone()))
+            .filter_map(|(id, component)| Some((key(component?), id)))
+            .collect::<Vec<_>>();
+        new.sort_unstable();
+        self.merge(new);
+    }
+    /// Merges the sorted entries of `new` into the order, moving each
+    /// existing entry at most once.
+    fn merge(&mut self, new: Vec<(K, u32)>) {
+        if new.is_empty() {
+            return;
+        }
+        let old_len = self.order.len();
+        let mut new = new.into_iter().rev().peekable();
+        let mut old = std::mem::take(&mut self.order);
+        let mut merged = Vec::with_capacity(old_len + new.len());
+        // Both are taken from their largest entry, then reversed.
+        while let Some(last) = old.last() {
+            match new.peek() {
+                Some(n) if n > last => merged.push(new.next().unwrap()),
+                _ => merged.push(old.pop().unwrap()),
+            }
+        }
+        merged.extend(new);
+        merged.reverse();
+        self.order = merged;
+    }
+    /// Returns the amount of indexed components.
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+    /// Returns true if no component is indexed.
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+    /// Iterates over the entity indices, in increasing key order.
+    pub fn indices(&self) -> impl DoubleEndedIterator<Item = u32> + ExactSizeIterator + '_ {
+        self.order.iter().map(|(_, id)| *id)
+    }
+    /// Iterates over the values of `terms`, in increasing key order.
+    /// Entities not matched by `terms` are skipped.
+    /// Use `.rev()` to iterate in decreasing key order.
+    pub fn join<J: Join>(&self, terms: J) -> SortedJoinIter<'_, J, K> {
+        SortedJoinIter {
+            order: self.order.iter(),
+            values: terms.open(),
+        }
+    }
+}
+
+/// Iterator over the values of a `Join` in the order of a `SortedIndex`.
+pub struct SortedJoinIter<'a, J: Join, K> {
+    order: std::slice::Iter<'a, (K, u32)>,
+    values: J::Values,
+}
+
+impl<'a, J: Join, K> Iterator for SortedJoinIter<'a, J, K> {
+    type Item = J::Item;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let id = self.order.next()?.1 as usize;
+            if J::contains(&self.values, id) {
+                // Unsafe: the index is matched and appears once in the order.
+                return Some(unsafe { J::fetch(&mut self.values, id) });
+            }
+        }
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.order.size_hint().1)
+    }
+}
+
+impl<'a, J: Join, K> DoubleEndedIterator for SortedJoinIter<'a, J, K> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            let id = self.order.next_back()?.1 as usize;
+            if J::contains(&self.values, id) {
+                // Unsafe: the index is matched and appears once in the order.
+                return Some(unsafe { J::fetch(&mut self.values, id) });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    #[test]
+    fn sorted_by_key() {
+        struct Depth(i32);
+        struct Name(&'static str);
+        let mut entities = Entities::default();
+        let mut depths = Components::<Depth>::default().with_change_tracking();
+        let mut names = Components::<Name>::default();
+        let mut order = SortedIndex::new(|d: &Depth| d.0);
+        for (depth, name) in [(3, "c"), (1, "a"), (2, "b"), (5, "e")].iter() {
+            let e = entities.create();
+            depths.insert(e, Depth(*depth));
+            names.insert(e, Name(name));
+        }
+        let e4 = entities.create();
+        depths.insert(e4, Depth(4));
+
+        order.update(&depths);
+        let sorted = order.join(&names).map(|n| n.0).collect::<String>();
+        assert_eq!(sorted, "abce");
+        let sorted = order.join((&entities, &depths)).rev().map(|(e, _)| e.index()).collect::<Vec<_>>();
+        assert_eq!(sorted, vec![3, 4, 0, 2, 1]);
+
+        // Only the modified components are moved.
+        join!(&mut depths && &names).filter(|(_, n)| n.0 == "e").for_each(|(d, _)| d.0 = 0);
+        let first = entities.iter_with_bitset(depths.bitset().clone()).flatten().next().unwrap();
+        depths.remove(first);
+        names.insert(e4, Name("d"));
+        order.update(&depths);
+        assert_eq!(order.len(), 4);
+        let sorted = order.join((&mut names, &depths)).map(|(n, d)| (n.0, d.0)).collect::<Vec<_>>();
+        assert_eq!(sorted, vec![("e", 0), ("a", 1), ("b", 2), ("d", 4)]);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::{Entities, Entity};
+
+/// A reference to an `Entity` that may have been killed since.
+///
+/// Unlike a plain `Entity`, the referenced entity can only be obtained
+/// through `Entities`, which checks that it is still alive.
+/// Useful in components pointing to other entities, such as a `Target`.
+/// ```rust,ignore
+/// struct Target(WeakEntity);
+/// if let Some(target) = target.0.get(&entities) {}
+/// ```
+#[cfg_attr(feature = "ser", derive(Serialize, Deserialize))]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct WeakEntity(Option<Entity>);
+
+impl WeakEntity {
+    /// Creates a reference to `entity`.
+    pub fn new(entity: Entity) -> Self {
+        WeakEntity(Some(entity))
+    }
+    /// Returns the referenced `Entity` if it is still alive.
+    pub fn get(&self, entities: &Entities) -> Option<Entity> {
+        self.0.filter(|e| entities.is_alive(*e))
+    }
+    /// Returns true if the referenced `Entity` is still alive.
+    pub fn is_alive(&self, entities: &Entities) -> bool {
+        self.get(entities).is_some()
+    }
+    /// Returns true if the reference was cleared by `purge_dead`, or was
+    /// never set.
+    pub fn is_cleared(&self) -> bool {
+        self.0.is_none()
+    }
+}
+
+impl From<Entity> for WeakEntity {
+    fn from(entity: Entity) -> Self {
+        WeakEntity::new(entity)
+    }
+}
+
+/// Values holding `WeakEntity` references, which can be purged in bulk
+/// with `Components::purge_dead`.
+pub trait WeakReferences {
+    /// Clears the references to entities that are no longer alive.
+    /// Returns true if anything was cleared.
+    fn purge_dead(&mut self, entities: &Entities) -> bool;
+}
+
+impl WeakReferences for WeakEntity {
+    fn purge_dead(&mut self, entities: &Entities) -> bool {
+        if self.0.is_some() && !self.is_alive(entities) {
+            self.0 = None;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl<W: WeakReferences> WeakReferences for Option<W> {
+    fn purge_dead(&mut self, entities: &Entities) -> bool {
+        self.as_mut().is_some_and(|w| w.purge_dead(entities))
+    }
+}
+
+/// Dead references are removed from the list.
+impl WeakReferences for Vec<WeakEntity> {
+    fn purge_dead(&mut self, entities: &Entities) -> bool {
+        let len = self.len();
+        self.retain(|w| w.is_alive(entities));
+        self.len() != len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    #[test]
+    fn purge_weak_references() {
+        struct Target(WeakEntity);
+        impl WeakReferences for Target {
+            fn purge_dead(&mut self, entities: &Entities) -> bool {
+                self.0.purge_dead(entities)
+            }
+        }
+        let mut entities = Entities::default();
+        let mut targets = Components::<Target>::default().with_change_tracking();
+        let mut lists = Components::<Vec<WeakEntity>>::default();
+        let e1 = entities.create();
+        let e2 = entities.create();
+        let e3 = entities.create();
+        targets.insert(e1, Target(e2.into()));
+        targets.insert(e2, Target(e3.into()));
+        lists.insert(e1, vec![e2.into(), e3.into()]);
+        assert_eq!(targets.get(e1).unwrap().0.get(&entities), Some(e2));
+
+        entities.kill(e3);
+        entities.clear_killed();
+        let e4 = entities.create();
+        assert_eq!(e4.index(), e3.index());
+        assert_eq!(targets.get(e2).unwrap().0.get(&entities), None);
+
+        let since = targets.tick();
+        targets.purge_dead(&entities);
+        lists.purge_dead(&entities);
+        assert!(targets.get(e2).unwrap().0.is_cleared());
+        assert!(!targets.get(e1).unwrap().0.is_cleared());
+        assert_eq!(lists.get(e1).unwrap(), &vec![WeakEntity::new(e2)]);
+        assert_eq!(targets.changed(since).count(), 1);
+    }
+}